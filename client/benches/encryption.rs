@@ -2,7 +2,10 @@ use aes_siv::{
     aead::{Aead, OsRng},
     Aes256SivAead, KeyInit, Nonce,
 };
-use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use rammingen::encryption::{encrypt_file, Cipher};
+use std::io::Write;
+use tempfile::NamedTempFile;
 
 fn criterion_benchmark(c: &mut Criterion) {
     let key = Aes256SivAead::generate_key(&mut OsRng);
@@ -12,6 +15,7 @@ fn criterion_benchmark(c: &mut Criterion) {
     //c.bench_function("encrypt", |b| {
     let mut group = c.benchmark_group("encrypt");
     for size in [1024, 1024 * 1024] {
+        group.throughput(Throughput::Bytes(size as u64));
         group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
             b.iter_batched(
                 || (0..size).map(|_| rand::random::<u8>()).collect::<Vec<u8>>(),
@@ -23,5 +27,30 @@ fn criterion_benchmark(c: &mut Criterion) {
     //});
 }
 
-criterion_group!(benches, criterion_benchmark);
+fn encrypt_file_benchmark(c: &mut Criterion) {
+    let key = Aes256SivAead::generate_key(&mut OsRng);
+    let cipher = Cipher::new(&key, false);
+
+    let mut group = c.benchmark_group("encrypt_file");
+    group.sample_size(10);
+    for size in [1024 * 1024, 64 * 1024 * 1024] {
+        let random_content = (0..size).map(|_| rand::random::<u8>()).collect::<Vec<u8>>();
+        let text_content = (0..)
+            .flat_map(|i| format!("line {i}\n").into_bytes())
+            .take(size)
+            .collect::<Vec<u8>>();
+        for (name, content) in [("random", random_content), ("text", text_content)] {
+            let mut file = NamedTempFile::new().unwrap();
+            file.write_all(&content).unwrap();
+            file.flush().unwrap();
+
+            group.throughput(Throughput::Bytes(size as u64));
+            group.bench_with_input(BenchmarkId::new(name, size), &size, |b, _| {
+                b.iter(|| encrypt_file(file.path(), &cipher).unwrap());
+            });
+        }
+    }
+}
+
+criterion_group!(benches, criterion_benchmark, encrypt_file_benchmark);
 criterion_main!(benches);
@@ -0,0 +1,152 @@
+//! Adoption of local files that already exist when a mount point is set up on a new machine.
+
+use std::io::ErrorKind;
+
+use anyhow::{anyhow, bail, Result};
+use fs_err as fs;
+use rammingen_protocol::{DateTimeUtc, EntryKind};
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+use crate::{
+    config::MountPoint,
+    data::{DecryptedFileContent, LocalEntryInfo},
+    download::archive_to_local_path,
+    encryption::hash_file,
+    pull_updates::pull_updates,
+    rules::Rules,
+    term::set_status,
+    trash::trash_file,
+    Ctx,
+};
+
+#[derive(Debug, Default)]
+struct AdoptStats {
+    adopted: u64,
+    mismatched: u64,
+}
+
+/// Compares existing local files with the latest archive entries of all mount points.
+/// Matching files are recorded in the local db as synced without touching them.
+///
+/// Mismatched local files are uploaded on the next sync, unless `replace_mismatched`
+/// is set. In that case, they are moved to the trash, so the next sync downloads
+/// the archive versions instead.
+pub async fn adopt(ctx: &Ctx, replace_mismatched: bool, dry_run: bool) -> Result<()> {
+    if replace_mismatched && ctx.config.trash_retention.is_zero() {
+        bail!("--replace-mismatched requires the trash to be enabled (`trash_retention` is zero)");
+    }
+    pull_updates(ctx).await?;
+    let mut stats = AdoptStats::default();
+    for mount_point in &ctx.config.mount_points {
+        let _status = set_status(format!("Adopting local files: {}", mount_point.local_path));
+        block_in_place(|| {
+            adopt_mount_point(ctx, mount_point, replace_mismatched, dry_run, &mut stats)
+        })?;
+    }
+    if dry_run {
+        info!(
+            "Would adopt {} entries, {} entries don't match the archive",
+            stats.adopted, stats.mismatched
+        );
+    } else {
+        info!(
+            "Adopted {} entries, {} entries don't match the archive",
+            stats.adopted, stats.mismatched
+        );
+    }
+    Ok(())
+}
+
+fn adopt_mount_point(
+    ctx: &Ctx,
+    mount_point: &MountPoint,
+    replace_mismatched: bool,
+    dry_run: bool,
+    stats: &mut AdoptStats,
+) -> Result<()> {
+    if ctx
+        .db
+        .get_archive_entry(&mount_point.archive_path)?
+        .is_none()
+    {
+        return Ok(());
+    }
+    let mut rules = Rules::new(
+        &[&ctx.config.always_exclude, &mount_point.exclude],
+        mount_point.local_path.clone(),
+    );
+    for entry in ctx.db.get_archive_entries(&mount_point.archive_path) {
+        let entry = entry?;
+        let Some(kind) = entry.kind else {
+            continue;
+        };
+        let local_path = archive_to_local_path(
+            &entry.path,
+            &mount_point.archive_path,
+            &mount_point.local_path,
+        )?;
+        if rules.matches_with_kind(&local_path, kind)?
+            || ctx.db.get_local_entry(&local_path)?.is_some()
+        {
+            continue;
+        }
+        let metadata = match fs::symlink_metadata(&local_path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if metadata.is_symlink() {
+            continue;
+        }
+        let content = match kind {
+            EntryKind::Directory if metadata.is_dir() => Some(None),
+            EntryKind::File if metadata.is_file() => {
+                let content = entry
+                    .content
+                    .ok_or_else(|| anyhow!("missing content info for existing file"))?;
+                if metadata.len() == content.original_size
+                    && hash_file(&local_path)? == content.hash
+                {
+                    // Unix mode is taken from the archive, so that a different local mode
+                    // is uploaded on the next sync.
+                    Some(Some(DecryptedFileContent {
+                        modified_at: DateTimeUtc::from(metadata.modified()?),
+                        ..content
+                    }))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        let Some(content) = content else {
+            stats.mismatched += 1;
+            if replace_mismatched && metadata.is_file() {
+                if dry_run {
+                    info!("Would move mismatched file to trash: {}", local_path);
+                } else {
+                    trash_file(
+                        ctx.config.trash_retention,
+                        &mount_point.local_path,
+                        &local_path,
+                    )?;
+                    info!("Moved mismatched file to trash: {}", local_path);
+                }
+            } else {
+                warn!("Local entry doesn't match the archive: {}", local_path);
+            }
+            continue;
+        };
+        if dry_run {
+            info!("Would adopt {}", local_path);
+        } else {
+            ctx.db
+                .set_local_entry(&local_path, &LocalEntryInfo { kind, content })?;
+            info!("Adopted {}", local_path);
+        }
+        stats.adopted += 1;
+    }
+    Ok(())
+}
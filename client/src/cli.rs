@@ -1,6 +1,9 @@
-use std::{path::PathBuf, str::FromStr};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use chrono::{DateTime, FixedOffset, Local, NaiveDateTime, TimeZone};
 use clap::{Parser, Subcommand};
 use derive_more::{From, Into};
@@ -23,6 +26,12 @@ pub struct Cli {
     /// - %APPDATA%\rammingen.conf on Windows
     #[clap(long)]
     pub config: Option<PathBuf>,
+    /// Record `privacy_padding` from the config for a local db that was synced
+    /// before the value was stored in it.
+    ///
+    /// Only use it if the config value is the one the archive was created with.
+    #[clap(long)]
+    pub confirm_privacy_padding: bool,
     #[clap(subcommand)]
     pub command: Command,
 }
@@ -32,12 +41,43 @@ pub enum Command {
     /// Show what will happen on sync.
     DryRun,
     /// Sync all mount point with the server.
-    Sync,
+    Sync {
+        /// Sync even if local changes exceed `mass_change_limits` from the config.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Sync all mount points with the server, then wait for changes on the server
+    /// and sync again every time they appear.
+    ///
+    /// Failed syncs are retried with increasing delays. Watch stops if local changes
+    /// exceed `mass_change_limits`.
+    Watch,
+    /// Record existing local files that match the archive as synced, without downloading them.
+    /// Useful when a mount point already contains a copy of the archive, e.g. on a new machine.
+    ///
+    /// Local files that don't match the archive are uploaded on the next sync.
+    Adopt {
+        /// Move local files that don't match the archive to the trash,
+        /// so that the archive versions are downloaded on the next sync.
+        /// Requires the trash to be enabled.
+        #[arg(long)]
+        replace_mismatched: bool,
+        /// Only show the changes without applying them.
+        #[arg(long)]
+        dry_run: bool,
+    },
     /// Upload a file or directory to the server.
     Upload {
         local_path: SanitizedLocalPath,
         archive_path: ArchivePath,
     },
+    /// Upload data from stdin to a file on the server.
+    UploadStream {
+        archive_path: ArchivePath,
+        /// Unix permissions of the file in octal form, e.g. 0600.
+        #[arg(long)]
+        mode: Option<UnixModeArg>,
+    },
     /// Download a file or directory from the server.
     Download {
         archive_path: ArchivePath,
@@ -47,8 +87,20 @@ pub enum Command {
         /// Accepted timestamp format: %Y-%m-%d_%H:%M:%S
         version: Option<DateTimeArg>,
     },
+    /// Writes content of an archived file to stdout.
+    Cat {
+        archive_path: ArchivePath,
+        /// Timestamp of the version to be written (in local time zone).
+        /// If omitted, the latest version is written.
+        /// Accepted timestamp format: %Y-%m-%d_%H:%M:%S
+        #[arg(long)]
+        at: Option<DateTimeArg>,
+    },
     /// Shows information about a local path.
     LocalStatus { path: SanitizedLocalPath },
+    /// Shows which exclude rule excludes a local path. If the path is a directory
+    /// that is not excluded, shows all excluded paths in it.
+    ExplainExclude { path: SanitizedLocalPath },
     /// Shows information about an archive path.
     Ls {
         path: ArchivePath,
@@ -68,14 +120,74 @@ pub enum Command {
         archive_path: ArchivePath,
         /// Accepted timestamp format: %Y-%m-%d_%H:%M:%S
         version: DateTime<FixedOffset>,
+        /// Only show the changes without applying them.
+        #[arg(long)]
+        dry_run: bool,
     },
     /// Move (rename) data from one archive path to another.
     Move {
         old_path: ArchivePath,
         new_path: ArchivePath,
+        /// Only show the changes without applying them.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Copy data from one archive path to another without uploading content again.
+    Copy {
+        old_path: ArchivePath,
+        new_path: ArchivePath,
+        /// Copy the version that was current at this time (in local time zone).
+        /// If omitted, the latest version is copied.
+        /// Accepted timestamp format: %Y-%m-%d_%H:%M:%S
+        #[arg(long)]
+        at: Option<DateTimeArg>,
     },
     /// Remove an archive path.
-    Remove { archive_path: ArchivePath },
+    Remove {
+        archive_path: ArchivePath,
+        /// Only show the changes without applying them.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Permanently delete an archive path and all its history from the server.
+    ///
+    /// Synced local copies are removed on the next sync, like other deleted paths
+    /// (moved to the trash if it's enabled).
+    Purge {
+        archive_path: ArchivePath,
+        /// Don't ask for confirmation.
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Shows the list of operations that changed archive entries.
+    Operations {
+        /// Only show operations that affected this path, its parents or nested paths.
+        #[arg(short, long)]
+        path: Option<ArchivePath>,
+        /// Only show operations recorded after this time (in local time zone).
+        /// Accepted timestamp format: %Y-%m-%d_%H:%M:%S
+        #[arg(short, long)]
+        since: Option<DateTimeArg>,
+    },
+    /// Restores paths affected by a move, remove, reset or undo operation
+    /// to their state before the operation.
+    Undo {
+        /// Operation id as shown by the `operations` command.
+        operation_id: i64,
+        /// Undo even if some of the affected paths were changed after the operation.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Manages mount points. The config file is rewritten, so its comments are lost.
+    MountPoint {
+        #[command(subcommand)]
+        command: MountPointCommand,
+    },
+    /// Manages files deleted or replaced by sync.
+    Trash {
+        #[command(subcommand)]
+        command: TrashCommand,
+    },
     /// Shows server status.
     Status,
     /// Initiates an integrity check on the server.
@@ -84,6 +196,40 @@ pub enum Command {
     GenerateEncryptionKey,
 }
 
+#[derive(Debug, Subcommand, PartialEq, Eq)]
+pub enum TrashCommand {
+    /// Shows files in the local trash.
+    List,
+    /// Moves a file from the local trash back to its original path.
+    Restore {
+        /// Trash item id as shown by `trash list`.
+        id: String,
+    },
+}
+
+#[derive(Debug, Subcommand, PartialEq, Eq)]
+pub enum MountPointCommand {
+    /// Change local path of a mount point, keeping its sync state.
+    /// Local files should be moved to the new path separately.
+    Move {
+        old_local_path: SanitizedLocalPath,
+        new_local_path: SanitizedLocalPath,
+    },
+    /// Remove a mount point from the config and forget its sync state.
+    /// Local files and archive entries are not affected.
+    Remove { local_path: SanitizedLocalPath },
+}
+
+/// Returns `config` or the default config path if it's not specified.
+pub fn config_path(config: Option<&Path>) -> Result<PathBuf> {
+    if let Some(config) = config {
+        Ok(config.to_path_buf())
+    } else {
+        let config_dir = dirs::config_dir().ok_or_else(|| anyhow!("cannot find config dir"))?;
+        Ok(config_dir.join("rammingen.conf"))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, From, Into)]
 pub struct DateTimeArg(pub DateTimeUtc);
 
@@ -101,3 +247,18 @@ impl FromStr for DateTimeArg {
         ))
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, From, Into)]
+pub struct UnixModeArg(pub u32);
+
+impl FromStr for UnixModeArg {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        let mode = u32::from_str_radix(input, 8)?;
+        if mode > 0o7777 {
+            bail!("invalid mode: {}", input);
+        }
+        Ok(Self(mode))
+    }
+}
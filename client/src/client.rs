@@ -1,4 +1,3 @@
-use aes_siv::Aes256SivAead;
 use anyhow::{anyhow, bail, Result};
 use byteorder::{ByteOrder, LE};
 use derivative::Derivative;
@@ -28,7 +27,7 @@ use rammingen_protocol::{
 
 use crate::{
     data::DecryptedFileContent,
-    encryption::{encrypt_content_hash, Decryptor},
+    encryption::{encrypt_content_hash, Cipher, Decryptor},
 };
 
 #[derive(Derivative, Clone)]
@@ -204,7 +203,7 @@ impl Client {
         &self,
         content: &DecryptedFileContent,
         path: impl AsRef<Path>,
-        cipher: &Aes256SivAead,
+        cipher: &Cipher,
     ) -> Result<()> {
         let mut i = 0;
         loop {
@@ -231,7 +230,21 @@ impl Client {
         &self,
         content: &DecryptedFileContent,
         path: impl AsRef<Path>,
-        cipher: &Aes256SivAead,
+        cipher: &Cipher,
+    ) -> Result<()> {
+        let file = File::create(path.as_ref())?;
+        self.download_and_decrypt_to(content, file, cipher).await
+    }
+
+    /// Downloads and decrypts the content into `output` without retries.
+    ///
+    /// Decrypted data is written as it arrives, and the content hash is only checked
+    /// at the end, so `output` may receive invalid data if an error is returned.
+    pub async fn download_and_decrypt_to(
+        &self,
+        content: &DecryptedFileContent,
+        output: impl Write,
+        cipher: &Cipher,
     ) -> Result<()> {
         let encrypted_hash = encrypt_content_hash(&content.hash, cipher)?;
         let mut response = timeout(
@@ -260,8 +273,7 @@ impl Client {
             bail!("encrypted size mismatch");
         }
 
-        let file = File::create(path.as_ref())?;
-        let mut decryptor = Decryptor::new(cipher, file);
+        let mut decryptor = Decryptor::new(cipher, output);
         let mut actual_encrypted_size = 0;
 
         while let Some(chunk) = timeout(DEFAULT_TIMEOUT, response.chunk()).await?? {
@@ -1,5 +1,6 @@
 use aes_siv::aead::OsRng;
 use aes_siv::{Aes256SivAead, KeyInit};
+use anyhow::Result;
 use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
 use byte_unit::Byte;
 use core::fmt;
@@ -9,7 +10,9 @@ use rammingen_protocol::{serde_path_with_prefix, ArchivePath};
 use reqwest::Url;
 use serde::de::Error;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use typenum::U64;
 
 use crate::path::SanitizedLocalPath;
@@ -80,6 +83,16 @@ pub struct Config {
     pub server_url: Url,
     #[derivative(Debug = "ignore")]
     pub access_token: String,
+    /// Pad file content and archive path components before encryption, so that the server
+    /// can't infer exact file sizes and name lengths. The same value must be used by all clients
+    /// for the whole lifetime of the archive: encrypted paths depend on it,
+    /// so existing archive entries become unreachable if it's changed.
+    #[serde(default)]
+    pub privacy_padding: bool,
+    /// Encrypt paths and entry data in the local db. Switching it converts
+    /// the existing local db on the next run.
+    #[serde(default)]
+    pub encrypt_local_db: bool,
     #[serde(default)]
     pub local_db_path: Option<PathBuf>,
     #[serde(default)]
@@ -89,12 +102,83 @@ pub struct Config {
 
     #[serde(default = "default_warn_about_files_larger_than")]
     pub warn_about_files_larger_than: Byte,
+    #[serde(default)]
+    pub mass_change_limits: MassChangeLimits,
+    /// Files deleted or replaced by sync are kept in the local trash for this long
+    /// (e.g. "30days"). Zero disables the trash.
+    #[serde(default = "default_trash_retention", with = "humantime_serde")]
+    pub trash_retention: Duration,
+}
+
+/// Sync stops without changing anything if the number of local entries deleted or modified
+/// since the last sync exceeds any of these limits in any mount point.
+/// Percentages are relative to the number of local entries in the mount point.
+/// Limits that are not specified are not checked.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MassChangeLimits {
+    #[serde(default)]
+    pub max_deleted_entries: Option<u64>,
+    #[serde(default)]
+    pub max_deleted_percent: Option<f64>,
+    #[serde(default)]
+    pub max_modified_entries: Option<u64>,
+    #[serde(default)]
+    pub max_modified_percent: Option<f64>,
+}
+
+impl MassChangeLimits {
+    pub fn is_empty(&self) -> bool {
+        self.max_deleted_entries.is_none()
+            && self.max_deleted_percent.is_none()
+            && self.max_modified_entries.is_none()
+            && self.max_modified_percent.is_none()
+    }
+}
+
+impl Config {
+    /// Replaces the config file at `path` with the current config.
+    ///
+    /// The new content is written to a temporary file first, so the config file
+    /// is never left partially written. Comments and formatting of the original file are lost.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        // A leftover file from an interrupted save could have wider permissions.
+        match fs_err::remove_file(&tmp_path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        // The config contains secrets, so the temporary file must not be readable by others,
+        // and permissions of the original file must be preserved.
+        let mut options = fs_err::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(target_family = "unix")]
+        {
+            use fs_err::os::unix::fs::OpenOptionsExt;
+
+            options.mode(0o600);
+        }
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+        // Otherwise the renamed file can be empty after a crash.
+        file.sync_all()?;
+        drop(file);
+        fs_err::set_permissions(&tmp_path, fs_err::metadata(path)?.permissions())?;
+        fs_err::rename(&tmp_path, path)?;
+        Ok(())
+    }
 }
 
 fn default_log_filter() -> String {
     "info".into()
 }
 
+fn default_trash_retention() -> Duration {
+    Duration::from_secs(30 * 24 * 3600)
+}
+
 fn default_warn_about_files_larger_than() -> Byte {
     "50 MB".parse().unwrap()
 }
@@ -12,6 +12,7 @@ pub struct Counters {
     pub uploaded_entries: AtomicU64,
     pub uploaded_large_files: AtomicU64,
     pub uploaded_bytes: AtomicU64,
+    pub moved_entries: AtomicU64,
 
     pub queued_download_entries: AtomicU64,
     pub queued_upload_entries: AtomicU64,
@@ -63,6 +64,15 @@ impl Counters {
             }
         }
 
+        let moved_entries = self.moved_entries.load(Ordering::Relaxed);
+        if moved_entries > 0 {
+            if dry_run {
+                info!("Would record {} moves", moved_entries);
+            } else {
+                info!("Recorded {} moves", moved_entries);
+            }
+        }
+
         let uploaded_entries = self.uploaded_entries.load(Ordering::Relaxed);
         let uploaded_bytes = self.uploaded_bytes.load(Ordering::Relaxed);
         if uploaded_entries > 0 || uploaded_bytes > 0 {
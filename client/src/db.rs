@@ -1,52 +1,140 @@
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use byteorder::{ByteOrder, LE};
 use rammingen_protocol::{ArchivePath, EntryKind, EntryUpdateNumber};
+use serde::{de::DeserializeOwned, Serialize};
 use sled::{transaction::ConflictableTransactionError, Transactional};
-use std::{fmt::Debug, io, iter, path::Path, str};
+use std::{fmt::Debug, io, iter, path::Path, path::MAIN_SEPARATOR, str};
 
 use crate::{
     data::{DecryptedEntryVersionData, LocalEntryInfo},
+    encryption::{decrypt_bytes, decrypt_str, encrypt_bytes, encrypt_str, Cipher},
     path::SanitizedLocalPath,
 };
 
 const KEY_LAST_ENTRY_UPDATE_NUMBER: [u8; 4] = [0, 0, 0, 1];
+const KEY_IS_ENCRYPTED: [u8; 4] = [0, 0, 0, 2];
+const KEY_PRIVACY_PADDING: [u8; 4] = [0, 0, 0, 3];
 
+/// Local database of the client.
+///
+/// If encryption is enabled, keys and values of `archive_entries` and `local_entries`
+/// are encrypted. Each path component of a key is encrypted separately with a zero nonce,
+/// so lookups by path and prefix scans still work, and a parent path is still ordered before its
+/// children. Values are encrypted with a random nonce.
 pub struct Db {
     #[allow(dead_code)]
     db: sled::Db,
     archive_entries: sled::Tree,
     local_entries: sled::Tree,
+    /// Inode numbers of local entries, used for rename detection.
+    /// Keys are the same as in `local_entries`.
+    local_inodes: sled::Tree,
+    cipher: Option<Cipher>,
 }
 
 impl Db {
-    pub fn open(path: &Path) -> Result<Db> {
+    /// Opens the database. If `cipher` is specified, the database is encrypted
+    /// with it. Otherwise, data is stored in plaintext.
+    ///
+    /// If existing data was stored in a different way, it's converted in place.
+    /// `old_cipher` is used to decrypt existing data if it was encrypted.
+    pub fn open(path: &Path, cipher: Option<Cipher>, old_cipher: &Cipher) -> Result<Db> {
         let db = sled::open(path)?;
-        Ok(Self {
+        let this = Self {
             archive_entries: db.open_tree("archive_entries")?,
             local_entries: db.open_tree("local_entries")?,
+            local_inodes: db.open_tree("local_inodes")?,
             db,
-        })
+            cipher,
+        };
+        let is_encrypted = this
+            .db
+            .get(KEY_IS_ENCRYPTED)?
+            .map_or(false, |value| *value == [1]);
+        if is_encrypted != this.cipher.is_some() {
+            this.convert(is_encrypted.then_some(old_cipher))?;
+        }
+        Ok(this)
+    }
+
+    /// Re-encodes all entries that were stored using `old_cipher`.
+    fn convert(&self, old_cipher: Option<&Cipher>) -> Result<()> {
+        let mut archive_entries = Vec::new();
+        for pair in self.archive_entries.iter() {
+            let (key, value) = pair?;
+            let data: DecryptedEntryVersionData = decode_value(&value, old_cipher)?;
+            let new_key =
+                encode_path(data.path.to_str_without_prefix(), '/', self.cipher.as_ref())?;
+            archive_entries.push((key, new_key, encode_value(&data, self.cipher.as_ref())?));
+        }
+        let mut local_entries = Vec::new();
+        for pair in self.local_entries.iter() {
+            let (key, value) = pair?;
+            let path = decode_path(&key, MAIN_SEPARATOR, old_cipher)?;
+            let data: LocalEntryInfo = decode_value(&value, old_cipher)?;
+            let new_key = encode_path(&path, MAIN_SEPARATOR, self.cipher.as_ref())?;
+            local_entries.push((key, new_key, encode_value(&data, self.cipher.as_ref())?));
+        }
+        let mut local_inodes = Vec::new();
+        for pair in self.local_inodes.iter() {
+            let (key, value) = pair?;
+            let path = decode_path(&key, MAIN_SEPARATOR, old_cipher)?;
+            let inode: u64 = decode_value(&value, old_cipher)?;
+            let new_key = encode_path(&path, MAIN_SEPARATOR, self.cipher.as_ref())?;
+            local_inodes.push((key, new_key, encode_value(&inode, self.cipher.as_ref())?));
+        }
+
+        (
+            &*self.db,
+            &self.archive_entries,
+            &self.local_entries,
+            &self.local_inodes,
+        )
+            .transaction(
+                |(db, archive_entries_tree, local_entries_tree, local_inodes_tree)| {
+                    for (tree, entries) in [
+                        (archive_entries_tree, &archive_entries),
+                        (local_entries_tree, &local_entries),
+                        (local_inodes_tree, &local_inodes),
+                    ] {
+                        for (old_key, _, _) in entries {
+                            tree.remove(old_key.clone())?;
+                        }
+                        for (_, new_key, new_value) in entries {
+                            tree.insert(new_key.as_slice(), new_value.as_slice())?;
+                        }
+                    }
+                    db.insert(&KEY_IS_ENCRYPTED, &[u8::from(self.cipher.is_some())])?;
+                    Ok::<_, ConflictableTransactionError<io::Error>>(())
+                },
+            )?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn archive_key(&self, path: &ArchivePath) -> Result<Vec<u8>> {
+        encode_path(path.to_str_without_prefix(), '/', self.cipher.as_ref())
+    }
+
+    fn local_key(&self, path: &SanitizedLocalPath) -> Result<Vec<u8>> {
+        encode_path(path.as_str(), MAIN_SEPARATOR, self.cipher.as_ref())
     }
 
     pub fn get_all_archive_entries(
         &self,
     ) -> impl Iterator<Item = Result<DecryptedEntryVersionData>> + DoubleEndedIterator {
+        let cipher = self.cipher.clone();
         self.archive_entries
             .iter()
-            .map(|pair| Ok(bincode::deserialize::<DecryptedEntryVersionData>(&pair?.1)?))
+            .map(move |pair| decode_value(&pair?.1, cipher.as_ref()))
     }
 
     pub fn get_archive_entry(
         &self,
         path: &ArchivePath,
     ) -> Result<Option<DecryptedEntryVersionData>> {
-        if let Some(value) = self
-            .archive_entries
-            .get(path.to_str_without_prefix().as_bytes())?
-        {
-            Ok(Some(bincode::deserialize::<DecryptedEntryVersionData>(
-                &value,
-            )?))
+        if let Some(value) = self.archive_entries.get(self.archive_key(path)?)? {
+            Ok(Some(decode_value(&value, self.cipher.as_ref())?))
         } else {
             Ok(None)
         }
@@ -57,27 +145,65 @@ impl Db {
         path: &ArchivePath,
     ) -> impl Iterator<Item = Result<DecryptedEntryVersionData>> + DoubleEndedIterator {
         let root_entry = (|| {
+            let key = self.archive_key(path)?;
             let value = self
                 .archive_entries
-                .get(path.to_str_without_prefix().as_bytes())?
+                .get(&key)?
                 .ok_or_else(|| anyhow!("no such archive path: {}", path))?;
-            anyhow::Ok(bincode::deserialize::<DecryptedEntryVersionData>(&value)?)
+            let entry = decode_value::<DecryptedEntryVersionData>(&value, self.cipher.as_ref())?;
+            anyhow::Ok((key, entry))
         })();
-        let children = if root_entry
-            .as_ref()
-            .map_or(false, |entry| entry.kind == Some(EntryKind::Directory))
-        {
-            let mut prefix = path.to_str_without_prefix().to_owned();
-            prefix.push('/');
-            Some(
-                self.archive_entries
-                    .scan_prefix(prefix)
-                    .map(|pair| Ok(bincode::deserialize::<DecryptedEntryVersionData>(&pair?.1)?)),
-            )
-        } else {
-            None
+        let children = match &root_entry {
+            Ok((key, entry)) if entry.kind == Some(EntryKind::Directory) => {
+                let mut prefix = key.clone();
+                prefix.push(b'/');
+                let cipher = self.cipher.clone();
+                Some(
+                    self.archive_entries
+                        .scan_prefix(prefix)
+                        .map(move |pair| decode_value(&pair?.1, cipher.as_ref())),
+                )
+            }
+            _ => None,
         };
-        iter::once(root_entry).chain(children.into_iter().flatten())
+        iter::once(root_entry.map(|(_, entry)| entry)).chain(children.into_iter().flatten())
+    }
+
+    /// Fails if `privacy_padding` differs from the value used when the archive was first synced
+    /// to this db. Archive paths are encrypted differently depending on it, so changing it
+    /// would make existing archive entries unreachable.
+    ///
+    /// The value is recorded automatically only if the db is empty. A db that was synced
+    /// before the value was recorded requires `confirmed`, because the config may already
+    /// differ from the value the archive was created with.
+    pub fn check_privacy_padding(&self, privacy_padding: bool, confirmed: bool) -> Result<()> {
+        match self.db.get(KEY_PRIVACY_PADDING)? {
+            Some(value) => {
+                let stored = *value == [1];
+                if stored != privacy_padding {
+                    bail!(
+                        "privacy_padding is {} in config, but the archive was created with {}; \
+                        it can't be changed for an existing archive",
+                        privacy_padding,
+                        stored
+                    );
+                }
+            }
+            None => {
+                let is_fresh = self.archive_entries.is_empty() && self.local_entries.is_empty();
+                if !is_fresh && !confirmed {
+                    bail!(
+                        "the local db doesn't record which privacy_padding the archive was \
+                        created with; if privacy_padding = {} is correct, run again with \
+                        --confirm-privacy-padding",
+                        privacy_padding
+                    );
+                }
+                self.db
+                    .insert(KEY_PRIVACY_PADDING, &[u8::from(privacy_padding)])?;
+            }
+        }
+        Ok(())
     }
 
     pub fn last_entry_update_number(&self) -> Result<EntryUpdateNumber> {
@@ -100,8 +226,8 @@ impl Db {
         (&*self.db, &self.archive_entries).transaction(|(db, archive_entries)| {
             for update in updates {
                 archive_entries.insert(
-                    update.path.to_str_without_prefix().as_bytes(),
-                    bincode::serialize(update).map_err(into_abort_err)?,
+                    self.archive_key(&update.path).map_err(into_abort_err)?,
+                    encode_value(update, self.cipher.as_ref()).map_err(into_abort_err)?,
                 )?;
             }
             db.insert(
@@ -117,33 +243,239 @@ impl Db {
         &self,
     ) -> impl Iterator<Item = Result<(SanitizedLocalPath, LocalEntryInfo)>> + DoubleEndedIterator
     {
-        self.local_entries.iter().map(|pair| {
+        let cipher = self.cipher.clone();
+        self.local_entries.iter().map(move |pair| {
             let (key, value) = pair?;
-            let path = SanitizedLocalPath::new(str::from_utf8(&key)?)?;
-            let data = bincode::deserialize::<LocalEntryInfo>(&value)?;
+            let path =
+                SanitizedLocalPath::new(decode_path(&key, MAIN_SEPARATOR, cipher.as_ref())?)?;
+            let data = decode_value::<LocalEntryInfo>(&value, cipher.as_ref())?;
             Ok((path, data))
         })
     }
 
     pub fn get_local_entry(&self, path: &SanitizedLocalPath) -> Result<Option<LocalEntryInfo>> {
-        if let Some(value) = self.local_entries.get(path)? {
-            Ok(Some(bincode::deserialize::<LocalEntryInfo>(&value)?))
+        if let Some(value) = self.local_entries.get(self.local_key(path)?)? {
+            Ok(Some(decode_value(&value, self.cipher.as_ref())?))
         } else {
             Ok(None)
         }
     }
 
     pub fn set_local_entry(&self, path: &SanitizedLocalPath, data: &LocalEntryInfo) -> Result<()> {
-        self.local_entries.insert(path, bincode::serialize(data)?)?;
+        self.local_entries.insert(
+            self.local_key(path)?,
+            encode_value(data, self.cipher.as_ref())?,
+        )?;
         Ok(())
     }
 
     pub fn remove_local_entry(&self, path: &SanitizedLocalPath) -> Result<()> {
-        self.local_entries.remove(path)?;
+        let key = self.local_key(path)?;
+        self.local_entries.remove(&key)?;
+        self.local_inodes.remove(key)?;
+        Ok(())
+    }
+
+    pub fn get_local_inode(&self, path: &SanitizedLocalPath) -> Result<Option<u64>> {
+        if let Some(value) = self.local_inodes.get(self.local_key(path)?)? {
+            Ok(Some(decode_value(&value, self.cipher.as_ref())?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn set_local_inode(&self, path: &SanitizedLocalPath, inode: u64) -> Result<()> {
+        self.local_inodes.insert(
+            self.local_key(path)?,
+            encode_value(&inode, self.cipher.as_ref())?,
+        )?;
         Ok(())
     }
+
+    /// Returns local entries at `path` and its nested paths.
+    fn get_local_entries_in(
+        &self,
+        path: &SanitizedLocalPath,
+    ) -> Result<Vec<(SanitizedLocalPath, LocalEntryInfo)>> {
+        let mut entries = Vec::new();
+        // Also matches sibling paths that have `path` as a string prefix,
+        // they are skipped by `starts_with`.
+        for pair in self.local_entries.scan_prefix(self.local_key(path)?) {
+            let (key, value) = pair?;
+            let entry_path =
+                SanitizedLocalPath::new(decode_path(&key, MAIN_SEPARATOR, self.cipher.as_ref())?)?;
+            if entry_path.as_path().starts_with(path) {
+                let data = decode_value(&value, self.cipher.as_ref())?;
+                entries.push((entry_path, data));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Returns true if there are local entries nested in `path`.
+    pub fn has_nested_local_entries(&self, path: &SanitizedLocalPath) -> Result<bool> {
+        for pair in self.local_entries.scan_prefix(self.local_key(path)?) {
+            let (key, _) = pair?;
+            let entry_path =
+                SanitizedLocalPath::new(decode_path(&key, MAIN_SEPARATOR, self.cipher.as_ref())?)?;
+            if &entry_path != path && entry_path.as_path().starts_with(path) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Moves local entries at `old_path` and its nested paths to `new_path`.
+    /// All entries are moved in a single transaction that is flushed to disk before returning.
+    pub fn move_local_entries(
+        &self,
+        old_path: &SanitizedLocalPath,
+        new_path: &SanitizedLocalPath,
+    ) -> Result<()> {
+        let mut moved = Vec::new();
+        for (path, data) in self.get_local_entries_in(old_path)? {
+            let relative = path.as_path().strip_prefix(old_path)?;
+            let target = if relative.as_os_str().is_empty() {
+                new_path.clone()
+            } else {
+                new_path.join(relative)?
+            };
+            let old_key = self.local_key(&path)?;
+            let inode = self.local_inodes.get(&old_key)?;
+            moved.push((
+                old_key,
+                self.local_key(&target)?,
+                encode_value(&data, self.cipher.as_ref())?,
+                inode,
+            ));
+        }
+        (&self.local_entries, &self.local_inodes).transaction(
+            |(local_entries, local_inodes)| {
+                for (old_key, _, _, _) in &moved {
+                    local_entries.remove(old_key.as_slice())?;
+                    local_inodes.remove(old_key.as_slice())?;
+                }
+                for (_, new_key, data, inode) in &moved {
+                    local_entries.insert(new_key.as_slice(), data.as_slice())?;
+                    if let Some(inode) = inode {
+                        local_inodes.insert(new_key.as_slice(), inode.clone())?;
+                    }
+                }
+                Ok::<_, ConflictableTransactionError<io::Error>>(())
+            },
+        )?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Removes local entries at `path` and its nested paths in a single transaction
+    /// that is flushed to disk before returning. Returns the number of removed entries.
+    pub fn remove_local_entries(&self, path: &SanitizedLocalPath) -> Result<usize> {
+        let keys = self
+            .get_local_entries_in(path)?
+            .iter()
+            .map(|(entry_path, _)| self.local_key(entry_path))
+            .collect::<Result<Vec<_>>>()?;
+        (&self.local_entries, &self.local_inodes).transaction(
+            |(local_entries, local_inodes)| {
+                for key in &keys {
+                    local_entries.remove(key.as_slice())?;
+                    local_inodes.remove(key.as_slice())?;
+                }
+                Ok::<_, ConflictableTransactionError<io::Error>>(())
+            },
+        )?;
+        self.db.flush()?;
+        Ok(keys.len())
+    }
+}
+
+fn encode_path(path: &str, separator: char, cipher: Option<&Cipher>) -> Result<Vec<u8>> {
+    let Some(cipher) = cipher else {
+        return Ok(path.as_bytes().to_vec());
+    };
+    let parts = path
+        .split(separator)
+        .map(|part| {
+            if part.is_empty() {
+                Ok(String::new())
+            } else {
+                encrypt_str(part, cipher)
+            }
+        })
+        .collect::<Result<Vec<String>>>()?;
+    Ok(parts.join(&separator.to_string()).into_bytes())
+}
+
+fn decode_path(key: &[u8], separator: char, cipher: Option<&Cipher>) -> Result<String> {
+    let key = str::from_utf8(key)?;
+    let Some(cipher) = cipher else {
+        return Ok(key.to_owned());
+    };
+    let parts = key
+        .split(separator)
+        .map(|part| {
+            if part.is_empty() {
+                Ok(String::new())
+            } else {
+                decrypt_str(part, cipher)
+            }
+        })
+        .collect::<Result<Vec<String>>>()?;
+    Ok(parts.join(&separator.to_string()))
+}
+
+fn encode_value(value: &impl Serialize, cipher: Option<&Cipher>) -> Result<Vec<u8>> {
+    let value = bincode::serialize(value)?;
+    if let Some(cipher) = cipher {
+        encrypt_bytes(&value, cipher)
+    } else {
+        Ok(value)
+    }
+}
+
+fn decode_value<T: DeserializeOwned>(value: &[u8], cipher: Option<&Cipher>) -> Result<T> {
+    if let Some(cipher) = cipher {
+        Ok(bincode::deserialize(&decrypt_bytes(value, cipher)?)?)
+    } else {
+        Ok(bincode::deserialize(value)?)
+    }
 }
 
 fn into_abort_err(e: impl Debug) -> ConflictableTransactionError<io::Error> {
     ConflictableTransactionError::Abort(io::Error::new(io::ErrorKind::Other, format!("{e:?}")))
 }
+
+#[test]
+fn encryption_migration() {
+    use aes_siv::{aead::OsRng, Aes256SivAead, KeyInit};
+
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("db");
+    let key = Aes256SivAead::generate_key(&mut OsRng);
+    let cipher = Cipher::new(&key, false);
+    let local_path = SanitizedLocalPath::new(dir.path()).unwrap();
+    let entry = LocalEntryInfo {
+        kind: EntryKind::Directory,
+        content: None,
+    };
+    Db::open(&db_path, None, &cipher)
+        .unwrap()
+        .set_local_entry(&local_path, &entry)
+        .unwrap();
+
+    for encrypt in [true, false, true] {
+        let db = Db::open(&db_path, encrypt.then(|| cipher.clone()), &cipher).unwrap();
+        assert_eq!(
+            db.local_entries.contains_key(local_path.as_str()).unwrap(),
+            !encrypt
+        );
+        assert!(db.get_local_entry(&local_path).unwrap().is_some());
+        let entries = db
+            .get_all_local_entries()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, local_path);
+    }
+}
@@ -4,7 +4,7 @@ use rammingen_protocol::util::interrupt_on_error;
 use sha2::Digest;
 use sha2::Sha256;
 use std::{
-    path::Path,
+    io::{self, BufWriter, Write},
     sync::{atomic::Ordering, Arc},
 };
 
@@ -27,12 +27,14 @@ use crate::{
     data::{DecryptedEntryVersionData, DecryptedFileContent, LocalEntryInfo},
     encryption::encrypt_path,
     path::SanitizedLocalPath,
+    pull_updates::pull_updates,
     rules::Rules,
     term::set_status,
+    trash::trash_file,
     Ctx,
 };
 
-fn archive_to_local_path(
+pub fn archive_to_local_path(
     path: &ArchivePath,
     root_archive_path: &ArchivePath,
     root_local_path: &SanitizedLocalPath,
@@ -47,15 +49,19 @@ fn archive_to_local_path(
     }
 }
 
-fn remove_dir_or_file(path: impl AsRef<Path>) -> Result<bool> {
-    let path = path.as_ref();
+/// Removes an empty directory or moves a file to the trash.
+fn remove_dir_or_file(
+    ctx: &Ctx,
+    root_local_path: &SanitizedLocalPath,
+    path: &SanitizedLocalPath,
+) -> Result<bool> {
     if fs_err::metadata(path)?.is_dir() {
         if let Err(err) = remove_dir(path) {
-            warn!("Cannot remove directory {}: {}", path.display(), err);
+            warn!("Cannot remove directory {}: {}", path, err);
             return Ok(false);
         }
     } else {
-        remove_file(path)?;
+        trash_file(ctx.config.trash_retention, root_local_path, path)?;
     }
     Ok(true)
 }
@@ -94,6 +100,49 @@ pub async fn download_version(
     .await
 }
 
+/// Writes content of the file at `archive_path` to stdout.
+/// If `version` is specified, the version that was current at that time is used.
+pub async fn cat(
+    ctx: &Ctx,
+    archive_path: &ArchivePath,
+    version: Option<DateTimeUtc>,
+) -> Result<()> {
+    let entry = if let Some(version) = version {
+        let mut response_stream = ctx.client.stream(&GetEntryVersionsAtTime {
+            path: encrypt_path(archive_path, &ctx.cipher)?,
+            recorded_at: version,
+        });
+        let mut found = None;
+        while let Some(entry) = response_stream.try_next().await? {
+            let entry = DecryptedEntryVersionData::new(ctx, entry.data)?;
+            if &entry.path == archive_path {
+                found = Some(entry);
+                break;
+            }
+        }
+        found
+    } else {
+        pull_updates(ctx).await?;
+        ctx.db.get_archive_entry(archive_path)?
+    };
+    let Some(entry) = entry else {
+        bail!("no such path: {}", archive_path);
+    };
+    let content = match entry.kind {
+        Some(EntryKind::File) => entry
+            .content
+            .ok_or_else(|| anyhow!("missing content info for existing file"))?,
+        Some(EntryKind::Directory) => bail!("{} is a directory", archive_path),
+        None => bail!("{} is deleted", archive_path),
+    };
+    let mut stdout = BufWriter::new(io::stdout());
+    ctx.client
+        .download_and_decrypt_to(&content, &mut stdout, &ctx.cipher)
+        .await?;
+    stdout.flush()?;
+    Ok(())
+}
+
 pub async fn download_latest(
     ctx: &Arc<Ctx>,
     root_archive_path: &ArchivePath,
@@ -193,19 +242,26 @@ async fn download_inner(
             }
             let entry_local_path =
                 archive_to_local_path(&entry.path, ctx.root_archive_path, ctx.root_local_path)?;
-            if ctx.rules.matches(&entry_local_path)? {
-                continue;
-            }
             let Some(db_data) = ctx.ctx.db.get_local_entry(&entry_local_path)? else {
                 continue;
             };
+            if ctx
+                .rules
+                .matches_with_kind(&entry_local_path, db_data.kind)?
+            {
+                continue;
+            }
             if try_exists(entry_local_path.as_path())? {
                 if ctx.dry_run {
                     info!("Would delete {}", entry_local_path);
                 } else {
                     match db_data.kind {
                         EntryKind::File => {
-                            remove_file(&entry_local_path)?;
+                            trash_file(
+                                ctx.ctx.config.trash_retention,
+                                ctx.root_local_path,
+                                &entry_local_path,
+                            )?;
                         }
                         EntryKind::Directory => {
                             if let Err(err) = remove_dir(&entry_local_path) {
@@ -233,7 +289,7 @@ async fn download_inner(
         };
         let entry_local_path =
             archive_to_local_path(&entry.path, ctx.root_archive_path, ctx.root_local_path)?;
-        if ctx.rules.matches(&entry_local_path)? {
+        if ctx.rules.matches_with_kind(&entry_local_path, kind)? {
             continue;
         }
         let _status = set_status(format!("Scanning remote files: {}", ctx.root_local_path));
@@ -294,6 +350,7 @@ async fn download_inner(
                 .send(FinalizeDownloadTaskItem {
                     entry,
                     db_data,
+                    root_local_path: ctx.root_local_path.clone(),
                     local_path: entry_local_path,
                     must_delete,
                     file_receiver,
@@ -402,6 +459,7 @@ async fn finalize_download_task(
 struct FinalizeDownloadTaskItem {
     entry: DecryptedEntryVersionData,
     db_data: Option<LocalEntryInfo>,
+    root_local_path: SanitizedLocalPath,
     local_path: SanitizedLocalPath,
     must_delete: bool,
     file_receiver: Option<oneshot::Receiver<TmpGuard>>,
@@ -432,7 +490,7 @@ async fn finalize_item_download(ctx: &Ctx, item: FinalizeDownloadTaskItem) -> Re
                 }
             }
             if item.must_delete {
-                if !remove_dir_or_file(&item.local_path)? {
+                if !remove_dir_or_file(ctx, &item.root_local_path, &item.local_path)? {
                     return Ok(());
                 }
             }
@@ -464,7 +522,7 @@ async fn finalize_item_download(ctx: &Ctx, item: FinalizeDownloadTaskItem) -> Re
                 }
             }
             if item.must_delete {
-                if !remove_dir_or_file(&item.local_path)? {
+                if !remove_dir_or_file(ctx, &item.root_local_path, &item.local_path)? {
                     return Ok(());
                 }
             }
@@ -15,7 +15,16 @@
 //! certain server operations. For example, if a MovePath or RemovePath command is issued,
 //! the server should be able to find all paths nested in the specified path.
 //!
-//! When encrypting file content, it's first compressed using deflate and then split into fixed-size blocks.
+//! An encrypted file starts with a header:
+//!
+//! - magic number (32 bits, little endian)
+//! - format version (8 bits)
+//! - codec id (8 bits) - compression algorithm applied to the content before encryption
+//!
+//! File content is compressed using zstd, unless a sample taken from the beginning
+//! of the file shows that it's not compressible (e.g. media files or archives).
+//! In that case, the content is stored without compression.
+//! The compressed content is then split into fixed-size blocks.
 //! For each block, a random nonce is chosen. The nonce and encrypted block data are written to the encrypted file
 //! in the following form:
 //!
@@ -23,28 +32,64 @@
 //! - nonce (128 bits) - the random nonce used to encrypt this block
 //! - encrypted content
 //!
+//! Each block is encrypted with associated data that consists of the format version (8 bits),
+//! the codec id (8 bits), the block index (64 bits, little endian, starting from 0) and
+//! the last block flags (8 bits: 0 for other blocks, 1 for the last block, 3 for the last block
+//! followed by padding). The last block is always written, even if it's empty.
+//! Thus, any change of the header, reordering, duplication or truncation of blocks is detected
+//! as soon as the first affected block is decrypted, and the decrypted content can be used
+//! without waiting for the whole file to be processed. Any data after the last block
+//! other than the expected padding is rejected.
+//!
+//! If privacy padding is enabled, the last block is followed by padding that makes
+//! the size of the encrypted file one of the sizes produced by the Padmé scheme.
+//! It hides the exact file size from the server. The padding starts with
+//! a padding marker (32 bits, all bits set) that replaces the next block size, and the rest of it is
+//! filled with zeros, which is checked by the decryptor. Privacy padding also makes each path component one of the fixed lengths
+//! before encryption (see `PATH_COMPONENT_LENGTHS`). Path components are padded with
+//! zero bytes that can't be present in a file name. The decryptor removes padding of
+//! content and paths regardless of the option.
+//!
+//! Format version 3 is the same, except it doesn't allow padding.
+//!
+//! Format version 2 is the same as version 3, except it doesn't use associated data, and the last block
+//! is not written if it's empty.
+//!
+//! Files written by older versions start with a different magic number and have no version
+//! and codec fields. Their content is always compressed using deflate. Such files
+//! are still accepted by the decryptor.
+//!
 //! Integrity of the file content is ensured on decryption by checking the resulting file content hash.
+//!
+//! Encryption of file content is parallelized. Hashing of the original content is done in
+//! a separate thread, large files are compressed using multiple zstd worker threads,
+//! and blocks are encrypted in the global thread pool and then written in the original order.
 
-use aes_siv::aead::Aead;
-use aes_siv::AeadCore;
+use aes_siv::aead::{Aead, Payload};
 use aes_siv::{aead::OsRng, Aes256SivAead, Nonce};
+use aes_siv::{AeadCore, KeyInit};
 use anyhow::{anyhow, bail, Result};
 use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
 use byteorder::{ByteOrder, WriteBytesExt, LE};
+use bytes::{Bytes, BytesMut};
 use deflate::write::DeflateEncoder;
 use deflate::CompressionOptions;
 use fs_err::File;
+use generic_array::GenericArray;
 use inflate::InflateWriter;
 use rammingen_protocol::{
     ArchivePath, ContentHash, EncryptedArchivePath, EncryptedContentHash, EncryptedSize,
 };
 use rand::RngCore;
-use sha2::{Digest, Sha256};
+use sha2::{Digest, Sha256, Sha512};
 use std::cmp::min;
-use std::io::{self, Write};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
 use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::{iter, mem, thread};
 use tempfile::SpooledTempFile;
-use typenum::ToInt;
+use typenum::{ToInt, U64};
 
 /// Max size of encrypted file content that will be stored in memory.
 /// Files exceeding this limit will be stored as a temporary file on disk.
@@ -53,14 +98,156 @@ const MAX_IN_MEMORY: usize = 32 * 1024 * 1024;
 /// Max length of a file chunk that will be encrypted at once.
 const BLOCK_SIZE: usize = 1024 * 1024;
 
+/// Length of a chunk that is read from the original file at once.
+const READ_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Max number of chunks waiting to be hashed.
+const MAX_PENDING_HASH_CHUNKS: usize = 16;
+
+/// Files of this size or larger are compressed using multiple threads.
+const MULTITHREADED_COMPRESSION_MIN_SIZE: u64 = 16 * 1024 * 1024;
+
 /// File type marker that is stored at the beginning of every encrypted file.
-const MAGIC_NUMBER: u32 = 3137690536;
+const MAGIC_NUMBER: u32 = 2838524217;
+
+/// File type marker of files written before the format header was introduced.
+const LEGACY_MAGIC_NUMBER: u32 = 3137690536;
+
+/// Version of the encrypted file format that is written by the encryptor.
+const FORMAT_VERSION: u8 = 4;
+
+/// Oldest format version that has a format header.
+const MIN_FORMAT_VERSION: u8 = 2;
+
+/// First format version that binds the header, block index and last block flags to each block.
+const AUTHENTICATED_ORDER_FORMAT_VERSION: u8 = 3;
+
+/// First format version that allows padding after the last block.
+const PADDING_FORMAT_VERSION: u8 = 4;
+
+/// Pseudo-version of files that have no format header.
+const LEGACY_FORMAT_VERSION: u8 = 1;
+
+/// Size of magic number, format version and codec id.
+const HEADER_SIZE: usize = 6;
+
+/// Written in place of the block size to indicate the start of the padding.
+const PADDING_MARKER: u32 = u32::MAX;
+
+/// If privacy padding is enabled, path components are padded to the closest of these lengths
+/// (in bytes). Longer components are padded to a multiple of the last length.
+const PATH_COMPONENT_LENGTHS: &[usize] = &[16, 32, 64, 128, 256];
+
+/// Size of the file sample that is used to check whether the file is compressible.
+const COMPRESSION_SAMPLE_SIZE: u64 = 128 * 1024;
+
+/// Compression level used to compress the sample. It should be fast.
+const SAMPLE_COMPRESSION_LEVEL: i32 = 1;
+
+/// Compression level used to compress file content.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// If compressed sample is larger than this percentage of the original sample,
+/// the file is stored without compression.
+const MAX_COMPRESSION_RATIO_PERCENT: usize = 95;
 
 // It should be a constant, but it currently doesn't work.
 fn nonce_size() -> usize {
     <Aes256SivAead as AeadCore>::NonceSize::to_int()
 }
 
+/// AES-SIV cipher and encryption options.
+#[derive(Clone)]
+pub struct Cipher {
+    // Shared with the threads that encrypt file content.
+    aead: Arc<Aes256SivAead>,
+    privacy_padding: bool,
+}
+
+impl Cipher {
+    pub fn new(key: &GenericArray<u8, U64>, privacy_padding: bool) -> Self {
+        Self {
+            aead: Arc::new(Aes256SivAead::new(key)),
+            privacy_padding,
+        }
+    }
+}
+
+/// Compression algorithm applied to file content before encryption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Uncompressed = 0,
+    Deflate = 1,
+    Zstd = 2,
+}
+
+impl TryFrom<u8> for Codec {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> io::Result<Self> {
+        match value {
+            0 => Ok(Self::Uncompressed),
+            1 => Ok(Self::Deflate),
+            2 => Ok(Self::Zstd),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("unknown codec id: {value}"),
+            )),
+        }
+    }
+}
+
+/// Returns associated data of a content block. `is_padded` means that the last block
+/// is followed by padding.
+fn block_aad(version: u8, codec: Codec, index: u64, is_last: bool, is_padded: bool) -> [u8; 11] {
+    let mut aad = [0u8; 11];
+    aad[0] = version;
+    aad[1] = codec as u8;
+    LE::write_u64(&mut aad[2..10], index);
+    aad[10] = u8::from(is_last) | (u8::from(is_last && is_padded) << 1);
+    aad
+}
+
+/// Returns the smallest size produced by the Padmé scheme that is not less than `size`.
+/// Padding overhead doesn't exceed 12%.
+fn padme(size: u64) -> u64 {
+    if size < 2 {
+        return size;
+    }
+    // floor(log2(size))
+    let exponent = u64::from(63 - size.leading_zeros());
+    // floor(log2(exponent)) + 1
+    let exponent_bits = u64::from(64 - exponent.leading_zeros());
+    let mask = (1u64 << (exponent - exponent_bits)) - 1;
+    (size + mask) & !mask
+}
+
+/// Pads a path component with zero bytes to one of `PATH_COMPONENT_LENGTHS`.
+fn pad_path_component(name: &str) -> String {
+    let max_length = *PATH_COMPONENT_LENGTHS
+        .last()
+        .expect("lengths must not be empty");
+    let length = PATH_COMPONENT_LENGTHS
+        .iter()
+        .copied()
+        .find(|&length| length >= name.len())
+        .unwrap_or_else(|| name.len().div_ceil(max_length) * max_length);
+    let mut padded = String::with_capacity(length);
+    padded.push_str(name);
+    padded.extend(iter::repeat('\0').take(length - name.len()));
+    padded
+}
+
+/// Chooses codec based on a sample taken from the beginning of the file.
+fn choose_codec(sample: &[u8]) -> io::Result<Codec> {
+    let compressed = zstd::bulk::compress(sample, SAMPLE_COMPRESSION_LEVEL)?;
+    if compressed.len() * 100 >= sample.len() * MAX_COMPRESSION_RATIO_PERCENT {
+        Ok(Codec::Uncompressed)
+    } else {
+        Ok(Codec::Zstd)
+    }
+}
+
 /// Passes through any writes and calculates Sha256 hash and size of the written data.
 struct HashingWriter<W> {
     hasher: Sha256,
@@ -100,63 +287,235 @@ impl<W: Write> Write for HashingWriter<W> {
     }
 }
 
+/// Compresses written data using the specified codec.
+enum Compressor<W: Write> {
+    Uncompressed(W),
+    Deflate(Box<DeflateEncoder<W>>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl<W: Write> Compressor<W> {
+    /// `num_workers` is the number of additional threads used for compression.
+    /// It's only supported by zstd.
+    fn new(output: W, codec: Codec, num_workers: u32) -> io::Result<Self> {
+        Ok(match codec {
+            Codec::Uncompressed => Self::Uncompressed(output),
+            Codec::Deflate => Self::Deflate(Box::new(DeflateEncoder::new(
+                output,
+                CompressionOptions::high(),
+            ))),
+            Codec::Zstd => {
+                let mut encoder = zstd::stream::write::Encoder::new(output, COMPRESSION_LEVEL)?;
+                if num_workers > 0 {
+                    encoder.multithread(num_workers)?;
+                }
+                Self::Zstd(encoder)
+            }
+        })
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Uncompressed(output) => Ok(output),
+            Self::Deflate(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Uncompressed(output) => output.write(buf),
+            Self::Deflate(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Uncompressed(output) => output.flush(),
+            Self::Deflate(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Decompresses written data using the specified codec.
+enum Decompressor<W: Write> {
+    Uncompressed(W),
+    Deflate(Box<InflateWriter<W>>),
+    Zstd(zstd::stream::write::Decoder<'static, W>),
+}
+
+impl<W: Write> Decompressor<W> {
+    fn new(output: W, codec: Codec) -> io::Result<Self> {
+        Ok(match codec {
+            Codec::Uncompressed => Self::Uncompressed(output),
+            Codec::Deflate => Self::Deflate(Box::new(InflateWriter::new(output))),
+            Codec::Zstd => Self::Zstd(zstd::stream::write::Decoder::new(output)?),
+        })
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Uncompressed(output) => Ok(output),
+            Self::Deflate(decoder) => decoder.finish(),
+            Self::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Decompressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Uncompressed(output) => output.write(buf),
+            Self::Deflate(decoder) => decoder.write(buf),
+            Self::Zstd(decoder) => decoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Uncompressed(output) => output.flush(),
+            Self::Deflate(decoder) => decoder.flush(),
+            Self::Zstd(decoder) => decoder.flush(),
+        }
+    }
+}
+
+/// Encrypts a block of file content. Returns the nonce followed by the ciphertext.
+fn encrypt_block(
+    aead: &Aes256SivAead,
+    data: &[u8],
+    codec: Codec,
+    index: u64,
+    is_last: bool,
+    is_padded: bool,
+) -> io::Result<Vec<u8>> {
+    let mut nonce = Nonce::default();
+    OsRng.fill_bytes(&mut nonce);
+
+    let ciphertext = aead
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: data,
+                aad: &block_aad(FORMAT_VERSION, codec, index, is_last, is_padded),
+            },
+        )
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
+    let mut output = Vec::with_capacity(nonce.len() + ciphertext.len());
+    output.extend_from_slice(&nonce);
+    output.extend_from_slice(&ciphertext);
+    Ok(output)
+}
+
 /// Writes encrypted blocks of file content.
-struct EncryptingWriter<'a, W> {
+///
+/// Blocks are encrypted in the global thread pool. Encrypted blocks are written
+/// in the original order.
+struct EncryptingWriter<W> {
     // Input data of the currently accumulated block.
     buf: Vec<u8>,
     output: W,
-    cipher: &'a Aes256SivAead,
+    aead: Arc<Aes256SivAead>,
+    codec: Codec,
+    privacy_padding: bool,
     encrypted_size: u64,
+    // Index of the next block.
+    block_index: u64,
+    // Receivers of the blocks that are being encrypted, ordered by block index.
+    pending_blocks: VecDeque<mpsc::Receiver<io::Result<Vec<u8>>>>,
 }
 
-impl<'a, W: Write> EncryptingWriter<'a, W> {
-    fn new(mut output: W, cipher: &'a Aes256SivAead) -> io::Result<Self> {
+impl<W: Write> EncryptingWriter<W> {
+    fn new(mut output: W, cipher: &Cipher, codec: Codec) -> io::Result<Self> {
         output.write_u32::<LE>(MAGIC_NUMBER)?;
+        output.write_u8(FORMAT_VERSION)?;
+        output.write_u8(codec as u8)?;
         Ok(Self {
             buf: Vec::new(),
             output,
-            cipher,
-            // size of magic number
-            encrypted_size: 4,
+            aead: cipher.aead.clone(),
+            codec,
+            privacy_padding: cipher.privacy_padding,
+            encrypted_size: HEADER_SIZE as u64,
+            block_index: 0,
+            pending_blocks: VecDeque::new(),
         })
     }
 
-    fn write_block(&mut self) -> io::Result<()> {
-        if self.buf.is_empty() {
-            return Ok(());
-        }
+    fn write_block(&mut self, is_last: bool) -> io::Result<()> {
         let input_len = min(self.buf.len(), BLOCK_SIZE);
-        let mut nonce = Nonce::default();
-        OsRng.fill_bytes(&mut nonce);
+        let rest = self.buf.split_off(input_len);
+        let data = mem::replace(&mut self.buf, rest);
 
-        let ciphertext = self
-            .cipher
-            .encrypt(&nonce, &self.buf[..input_len])
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
-        let output_size = nonce.len() + ciphertext.len();
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let aead = self.aead.clone();
+        let codec = self.codec;
+        let index = self.block_index;
+        let is_padded = self.privacy_padding;
+        rayon::spawn(move || {
+            let _ = sender.send(encrypt_block(
+                &aead, &data, codec, index, is_last, is_padded,
+            ));
+        });
+        self.pending_blocks.push_back(receiver);
+        self.block_index += 1;
 
-        self.output.write_u32::<LE>(output_size as u32)?;
-        self.output.write_all(&nonce)?;
-        self.output.write_all(&ciphertext)?;
-        self.encrypted_size += 4 + output_size as u64;
+        // Limit memory usage if encryption is slower than the input.
+        while self.pending_blocks.len() > 2 * rayon::current_num_threads() {
+            self.write_pending_block()?;
+        }
+        Ok(())
+    }
 
-        self.buf.drain(..input_len);
+    /// Waits for the oldest pending block to be encrypted and writes it.
+    fn write_pending_block(&mut self) -> io::Result<()> {
+        let Some(receiver) = self.pending_blocks.pop_front() else {
+            return Ok(());
+        };
+        let block = receiver
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption task failed"))??;
 
+        self.output.write_u32::<LE>(block.len() as u32)?;
+        self.output.write_all(&block)?;
+        self.encrypted_size += 4 + block.len() as u64;
         Ok(())
     }
 
     fn finish(mut self) -> io::Result<(W, u64)> {
-        self.write_block()?;
+        // `write` never leaves more than a block of data in the buffer.
+        self.write_block(true)?;
+        while !self.pending_blocks.is_empty() {
+            self.write_pending_block()?;
+        }
+        if self.privacy_padding {
+            let padded_size = padme(self.encrypted_size + 4);
+            self.output.write_u32::<LE>(PADDING_MARKER)?;
+            io::copy(
+                &mut io::repeat(0).take(padded_size - self.encrypted_size - 4),
+                &mut self.output,
+            )?;
+            self.encrypted_size = padded_size;
+        }
         self.output.flush()?;
         Ok((self.output, self.encrypted_size))
     }
 }
 
-impl<'a, W: Write> Write for EncryptingWriter<'a, W> {
+impl<W: Write> Write for EncryptingWriter<W> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         self.buf.extend_from_slice(buf);
-        if self.buf.len() >= BLOCK_SIZE {
-            self.write_block()?;
+        // A full block is only written when more data follows it,
+        // because we don't know yet whether it's the last block.
+        while self.buf.len() > BLOCK_SIZE {
+            self.write_block(false)?;
         }
         Ok(buf.len())
     }
@@ -173,15 +532,63 @@ pub struct EncryptedFileData {
     pub encrypted_size: u64,
 }
 
-pub fn encrypt_file(path: impl AsRef<Path>, cipher: &Aes256SivAead) -> Result<EncryptedFileData> {
-    let mut input_file = File::open(path.as_ref())?;
+pub fn encrypt_file(path: impl AsRef<Path>, cipher: &Cipher) -> Result<EncryptedFileData> {
+    let input_file = File::open(path.as_ref())?;
+    let num_compression_workers =
+        if input_file.metadata()?.len() >= MULTITHREADED_COMPRESSION_MIN_SIZE {
+            rayon::current_num_threads().try_into()?
+        } else {
+            0
+        };
+    encrypt_reader(input_file, cipher, num_compression_workers)
+}
+
+/// Encrypts all data from `input`. Use 0 `num_compression_workers`
+/// for single-threaded compression.
+pub fn encrypt_reader(
+    mut input: impl Read,
+    cipher: &Cipher,
+    num_compression_workers: u32,
+) -> Result<EncryptedFileData> {
+    let mut sample = Vec::new();
+    (&mut input)
+        .take(COMPRESSION_SAMPLE_SIZE)
+        .read_to_end(&mut sample)?;
+    let codec = choose_codec(&sample)?;
+
     let output = SpooledTempFile::new(MAX_IN_MEMORY);
-    let encryptor = EncryptingWriter::new(output, cipher)?;
-    let encoder = DeflateEncoder::new(encryptor, CompressionOptions::high());
-    let mut hasher = HashingWriter::new(encoder);
-    io::copy(&mut input_file, &mut hasher)?;
-    let (encoder, hash, original_size) = hasher.finish()?;
-    let encryptor = encoder.finish()?;
+    let encryptor = EncryptingWriter::new(output, cipher, codec)?;
+    let mut compressor = Compressor::new(encryptor, codec, num_compression_workers)?;
+    let (hash, original_size) = thread::scope(|scope| -> Result<_> {
+        let (chunk_sender, chunk_receiver) = mpsc::sync_channel::<Bytes>(MAX_PENDING_HASH_CHUNKS);
+        let hasher = scope.spawn(move || {
+            let mut hasher = Sha256::new();
+            let mut size = 0;
+            for chunk in chunk_receiver {
+                hasher.update(&chunk);
+                size += chunk.len() as u64;
+            }
+            (ContentHash::new(hasher.finalize().into()), size)
+        });
+
+        let mut chunk = Bytes::from(sample);
+        while !chunk.is_empty() {
+            chunk_sender
+                .send(chunk.clone())
+                .map_err(|_| anyhow!("hashing thread has stopped"))?;
+            compressor.write_all(&chunk)?;
+
+            let mut buf = BytesMut::zeroed(READ_CHUNK_SIZE);
+            let len = input.read(&mut buf)?;
+            buf.truncate(len);
+            chunk = buf.freeze();
+        }
+        drop(chunk_sender);
+        hasher
+            .join()
+            .map_err(|_| anyhow!("hashing thread panicked"))
+    })?;
+    let encryptor = compressor.finish()?;
     let (file, encrypted_size) = encryptor.finish()?;
     Ok(EncryptedFileData {
         file,
@@ -191,50 +598,128 @@ pub fn encrypt_file(path: impl AsRef<Path>, cipher: &Aes256SivAead) -> Result<En
     })
 }
 
+/// Calculates hash of the file content without encrypting it.
+pub fn hash_file(path: impl AsRef<Path>) -> Result<ContentHash> {
+    let mut writer = HashingWriter::new(io::sink());
+    io::copy(&mut File::open(path.as_ref())?, &mut writer)?;
+    let (_, hash, _) = writer.finish()?;
+    Ok(hash)
+}
+
 // Decrypts encrypted files.
 pub struct Decryptor<'a, W: Write> {
-    // Whether the magic number has been read.
-    got_header: bool,
+    // Output writer. It's taken when the header is read.
+    pending_output: Option<HashingWriter<W>>,
     // Input data that is not yet decrypted.
     buf: Vec<u8>,
-    cipher: &'a Aes256SivAead,
-    output: InflateWriter<HashingWriter<W>>,
+    cipher: &'a Cipher,
+    // Output writer. It's available after the header is read.
+    output: Option<Decompressor<HashingWriter<W>>>,
+    // Format version and codec. They are available after the header is read.
+    version: u8,
+    codec: Codec,
+    // Index of the next block.
+    block_index: u64,
+    // Whether the block marked as last has been decrypted.
+    got_last_block: bool,
+    // Whether the last block is followed by padding.
+    is_padded: bool,
 }
 
 impl<'a, W: Write> Decryptor<'a, W> {
-    pub fn new(cipher: &'a Aes256SivAead, output: W) -> Self {
+    pub fn new(cipher: &'a Cipher, output: W) -> Self {
         Self {
-            got_header: false,
+            pending_output: Some(HashingWriter::new(output)),
             buf: Vec::new(),
             cipher,
-            output: InflateWriter::new(HashingWriter::new(output)),
+            output: None,
+            version: 0,
+            codec: Codec::Uncompressed,
+            block_index: 0,
+            got_last_block: false,
+            is_padded: false,
         }
     }
 
     pub fn finish(mut self) -> io::Result<(W, ContentHash, u64)> {
-        self.process_block()?;
+        while self.process_block(true)? {}
         if !self.buf.is_empty() {
             return Err(io::Error::new(io::ErrorKind::Other, "trailing data found"));
         }
-        self.output.finish()?.finish()
+        if self.version >= AUTHENTICATED_ORDER_FORMAT_VERSION && !self.got_last_block {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "file is truncated (last block is missing)",
+            ));
+        }
+        self.output
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "missing file header"))?
+            .finish()?
+            .finish()
     }
 
-    fn process_block(&mut self) -> io::Result<()> {
-        if !self.got_header {
-            if self.buf.len() < 4 {
-                return Ok(());
+    /// Reads the file header if it's available. Returns `false` if more data is needed.
+    fn process_header(&mut self) -> io::Result<bool> {
+        if self.buf.len() < 4 {
+            return Ok(false);
+        }
+        let (version, codec, header_size) = match LE::read_u32(&self.buf) {
+            MAGIC_NUMBER => {
+                if self.buf.len() < HEADER_SIZE {
+                    return Ok(false);
+                }
+                let version = self.buf[4];
+                if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&version) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        format!("unsupported format version: {version}"),
+                    ));
+                }
+                (version, Codec::try_from(self.buf[5])?, HEADER_SIZE)
             }
-            if LE::read_u32(&self.buf) != MAGIC_NUMBER {
+            LEGACY_MAGIC_NUMBER => (LEGACY_FORMAT_VERSION, Codec::Deflate, 4),
+            _ => {
                 return Err(io::Error::new(
                     io::ErrorKind::Other,
                     "magic number mismatch",
                 ));
             }
-            self.buf.drain(..4);
-            self.got_header = true;
+        };
+        let output = self
+            .pending_output
+            .take()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "header is already processed"))?;
+        self.output = Some(Decompressor::new(output, codec)?);
+        self.version = version;
+        self.codec = codec;
+        self.buf.drain(..header_size);
+        Ok(true)
+    }
+
+    /// Decrypts the next block if it's available. Returns `false` if more data is needed.
+    ///
+    /// `at_end` indicates that no more data will be written.
+    fn process_block(&mut self, at_end: bool) -> io::Result<bool> {
+        if self.output.is_none() && !self.process_header()? {
+            return Ok(false);
+        }
+        if self.got_last_block {
+            // Only zeros of the padding can follow the last block. The padding marker
+            // is consumed along with the last block.
+            if !self.is_padded && !self.buf.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::Other, "trailing data found"));
+            }
+            if self.buf.iter().any(|&byte| byte != 0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "padding contains non-zero bytes",
+                ));
+            }
+            self.buf.clear();
+            return Ok(false);
         }
         if self.buf.len() < 4 {
-            return Ok(());
+            return Ok(false);
         }
         let len: usize = LE::read_u32(&self.buf)
             .try_into()
@@ -249,59 +734,139 @@ impl<'a, W: Write> Decryptor<'a, W> {
         }
         let rest_of_data = &self.buf[4..];
         if rest_of_data.len() < len {
-            return Ok(());
+            return Ok(false);
         }
+        let authenticated_order = self.version >= AUTHENTICATED_ORDER_FORMAT_VERSION;
+        let after_block = &rest_of_data[len..];
+        let (is_last, is_padded) = if !authenticated_order {
+            (false, false)
+        } else if after_block.len() >= 4 {
+            let is_padded = self.version >= PADDING_FORMAT_VERSION
+                && LE::read_u32(after_block) == PADDING_MARKER;
+            (is_padded, is_padded)
+        } else if at_end {
+            (after_block.is_empty(), false)
+        } else {
+            // We don't know yet whether it's the last block.
+            return Ok(false);
+        };
         let chunk_data = &rest_of_data[..len];
 
         let nonce = chunk_data
             .get(..nonce_size)
             .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "chunk data is too short"))?;
         let nonce = Nonce::from_slice(nonce);
-        let plaintext = self
-            .cipher
-            .decrypt(nonce, &chunk_data[nonce_size..])
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "decryption failed"))?;
-        self.output.write_all(&plaintext)?;
-        self.buf.drain(..4 + len);
-        Ok(())
+        let ciphertext = &chunk_data[nonce_size..];
+        let plaintext = if authenticated_order {
+            self.cipher.aead.decrypt(
+                nonce,
+                Payload {
+                    msg: ciphertext,
+                    aad: &block_aad(
+                        self.version,
+                        self.codec,
+                        self.block_index,
+                        is_last,
+                        is_padded,
+                    ),
+                },
+            )
+        } else {
+            self.cipher.aead.decrypt(nonce, ciphertext)
+        }
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("decryption failed for block {}", self.block_index),
+            )
+        })?;
+        self.output
+            .as_mut()
+            .expect("header must be processed")
+            .write_all(&plaintext)?;
+        let marker_len = if is_padded { 4 } else { 0 };
+        self.buf.drain(..4 + len + marker_len);
+        self.block_index += 1;
+        self.got_last_block = is_last;
+        self.is_padded = is_padded;
+        Ok(true)
     }
 }
 
 impl<'a, W: Write> Write for Decryptor<'a, W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.buf.extend_from_slice(buf);
-        self.process_block()?;
+        while self.process_block(false)? {}
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.output.flush()?;
+        if let Some(output) = &mut self.output {
+            output.flush()?;
+        }
         Ok(())
     }
 }
 
-pub fn encrypt_str(value: &str, cipher: &Aes256SivAead) -> Result<String> {
+pub fn encrypt_str(value: &str, cipher: &Cipher) -> Result<String> {
     let ciphertext = cipher
+        .aead
         .encrypt(&Nonce::default(), value.as_bytes())
         .map_err(|_| anyhow!("encryption failed"))?;
     Ok(BASE64_URL_SAFE_NO_PAD.encode(ciphertext))
 }
 
-pub fn decrypt_str(value: &str, cipher: &Aes256SivAead) -> Result<String> {
+pub fn decrypt_str(value: &str, cipher: &Cipher) -> Result<String> {
     let ciphertext = BASE64_URL_SAFE_NO_PAD.decode(value)?;
     let plaintext = cipher
+        .aead
         .decrypt(&Nonce::default(), ciphertext.as_slice())
         .map_err(|_| anyhow!("decryption failed for {:?}", value))?;
     Ok(String::from_utf8(plaintext)?)
 }
 
-pub fn encrypt_path(value: &ArchivePath, cipher: &Aes256SivAead) -> Result<EncryptedArchivePath> {
+/// Derives an independent key for the specified purpose from the main encryption key.
+pub fn derive_key(key: &GenericArray<u8, U64>, purpose: &str) -> GenericArray<u8, U64> {
+    Sha512::new()
+        .chain_update(purpose.as_bytes())
+        .chain_update([0])
+        .chain_update(key)
+        .finalize()
+}
+
+/// Encrypts arbitrary data using a random nonce. The nonce is written before the ciphertext.
+pub fn encrypt_bytes(value: &[u8], cipher: &Cipher) -> Result<Vec<u8>> {
+    let nonce = Aes256SivAead::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .aead
+        .encrypt(&nonce, value)
+        .map_err(|_| anyhow!("encryption failed"))?;
+    let mut output = nonce.to_vec();
+    output.extend_from_slice(&ciphertext);
+    Ok(output)
+}
+
+pub fn decrypt_bytes(value: &[u8], cipher: &Cipher) -> Result<Vec<u8>> {
+    let nonce_len = Nonce::default().len();
+    if value.len() < nonce_len {
+        bail!("decrypt_bytes: input is too short");
+    }
+    let (nonce, ciphertext) = value.split_at(nonce_len);
+    cipher
+        .aead
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("decryption failed"))
+}
+
+pub fn encrypt_path(value: &ArchivePath, cipher: &Cipher) -> Result<EncryptedArchivePath> {
     let parts = value
         .to_str_without_prefix()
         .split('/')
         .map(|part| {
             if part.is_empty() {
                 Ok(String::new())
+            } else if cipher.privacy_padding {
+                encrypt_str(&pad_path_component(part), cipher)
             } else {
                 encrypt_str(part, cipher)
             }
@@ -310,7 +875,7 @@ pub fn encrypt_path(value: &ArchivePath, cipher: &Aes256SivAead) -> Result<Encry
     EncryptedArchivePath::from_encrypted_without_prefix(&parts.join("/"))
 }
 
-pub fn decrypt_path(value: &EncryptedArchivePath, cipher: &Aes256SivAead) -> Result<ArchivePath> {
+pub fn decrypt_path(value: &EncryptedArchivePath, cipher: &Cipher) -> Result<ArchivePath> {
     let parts = value
         .to_str_without_prefix()
         .split('/')
@@ -318,42 +883,42 @@ pub fn decrypt_path(value: &EncryptedArchivePath, cipher: &Aes256SivAead) -> Res
             if part.is_empty() {
                 Ok(String::new())
             } else {
-                decrypt_str(part, cipher)
+                let mut part = decrypt_str(part, cipher)?;
+                part.truncate(part.trim_end_matches('\0').len());
+                Ok(part)
             }
         })
         .collect::<Result<Vec<String>>>()?;
     ArchivePath::from_str_without_prefix(&parts.join("/"))
 }
 
-pub fn encrypt_content_hash(
-    value: &ContentHash,
-    cipher: &Aes256SivAead,
-) -> Result<EncryptedContentHash> {
+pub fn encrypt_content_hash(value: &ContentHash, cipher: &Cipher) -> Result<EncryptedContentHash> {
     let ciphertext = cipher
+        .aead
         .encrypt(&Nonce::default(), value.as_slice())
         .map_err(|_| anyhow!("encryption failed"))?;
     Ok(EncryptedContentHash::from_encrypted(ciphertext))
 }
 
-pub fn decrypt_content_hash(
-    value: &EncryptedContentHash,
-    cipher: &Aes256SivAead,
-) -> Result<ContentHash> {
+pub fn decrypt_content_hash(value: &EncryptedContentHash, cipher: &Cipher) -> Result<ContentHash> {
     cipher
+        .aead
         .decrypt(&Nonce::default(), value.as_slice())
         .map_err(|_| anyhow!("decryption failed for {:?}", value))?
         .try_into()
 }
 
-pub fn encrypt_size(value: u64, cipher: &Aes256SivAead) -> Result<EncryptedSize> {
+pub fn encrypt_size(value: u64, cipher: &Cipher) -> Result<EncryptedSize> {
     let ciphertext = cipher
+        .aead
         .encrypt(&Nonce::default(), &value.to_le_bytes()[..])
         .map_err(|_| anyhow!("encryption failed"))?;
     Ok(EncryptedSize::from_encrypted(ciphertext))
 }
 
-pub fn decrypt_size(value: &EncryptedSize, cipher: &Aes256SivAead) -> Result<u64> {
+pub fn decrypt_size(value: &EncryptedSize, cipher: &Cipher) -> Result<u64> {
     let plaintext = cipher
+        .aead
         .decrypt(&Nonce::default(), value.as_slice())
         .map_err(|_| anyhow!("decryption failed for {:?}", value))?;
     if plaintext.len() != 8 {
@@ -367,10 +932,8 @@ pub fn decrypt_size(value: &EncryptedSize, cipher: &Aes256SivAead) -> Result<u64
 
 #[test]
 pub fn str_roundtrip() {
-    use aes_siv::KeyInit;
-
     let key = Aes256SivAead::generate_key(&mut OsRng);
-    let cipher = Aes256SivAead::new(&key);
+    let cipher = Cipher::new(&key, false);
     let value = "abcd1";
     let encrypted = encrypt_str(value, &cipher).unwrap();
     assert_ne!(value, encrypted);
@@ -380,10 +943,8 @@ pub fn str_roundtrip() {
 
 #[test]
 pub fn path_roundtrip() {
-    use aes_siv::KeyInit;
-
     let key = Aes256SivAead::generate_key(&mut OsRng);
-    let cipher = Aes256SivAead::new(&key);
+    let cipher = Cipher::new(&key, false);
     let value: ArchivePath = "ar:/ab/cd/ef".parse().unwrap();
     let encrypted = encrypt_path(&value, &cipher).unwrap();
     assert_ne!(
@@ -392,35 +953,75 @@ pub fn path_roundtrip() {
     );
     let decrypted = decrypt_path(&encrypted, &cipher).unwrap();
     assert_eq!(value, decrypted);
+
+    let padded_cipher = Cipher::new(&key, true);
+    let padded = encrypt_path(&value, &padded_cipher).unwrap();
+    assert_ne!(padded, encrypted);
+    assert_eq!(decrypt_path(&padded, &cipher).unwrap(), value);
+    let longer_value: ArchivePath = "ar:/abcd/cdefgh/e".parse().unwrap();
+    let longer_padded = encrypt_path(&longer_value, &padded_cipher).unwrap();
+    assert_eq!(
+        longer_padded.to_str_without_prefix().len(),
+        padded.to_str_without_prefix().len()
+    );
+    assert_eq!(
+        decrypt_path(&longer_padded, &padded_cipher).unwrap(),
+        longer_value
+    );
 }
 
 #[test]
-pub fn file_roundtrip() {
-    use aes_siv::KeyInit;
-    use std::io::{Read, Seek, SeekFrom};
-    use tempfile::NamedTempFile;
-
+pub fn bytes_roundtrip() {
     let key = Aes256SivAead::generate_key(&mut OsRng);
-    let cipher = Aes256SivAead::new(&key);
+    let cipher = Cipher::new(&derive_key(&key, "test"), false);
+    let value = b"abcd1";
+    let encrypted = encrypt_bytes(value, &cipher).unwrap();
+    assert_ne!(encrypt_bytes(value, &cipher).unwrap(), encrypted);
+    assert_eq!(decrypt_bytes(&encrypted, &cipher).unwrap(), value);
+    let other_cipher = Cipher::new(&key, false);
+    assert!(decrypt_bytes(&encrypted, &other_cipher).is_err());
+}
 
-    let mut file = NamedTempFile::new().unwrap();
-    for _ in 0..20000 {
-        let input: Vec<u8> = (0..1000).map(|_| rand::random::<u8>()).collect();
-        file.write_all(&input).unwrap();
+#[test]
+pub fn padme_sizes() {
+    assert_eq!(padme(0), 0);
+    assert_eq!(padme(1), 1);
+    assert_eq!(padme(9), 10);
+    assert_eq!(padme(1000), 1024);
+    assert_eq!(padme(1024 * 1024 + 1), 1024 * 1024 + 32 * 1024);
+    for size in [17, 555, 4097, 123456789] {
+        let padded = padme(size);
+        assert!(padded >= size);
+        assert!(padded - size <= size * 12 / 100);
+        assert_eq!(padme(padded), padded);
     }
-    file.flush().unwrap();
+}
 
-    let mut encrypted_file = encrypt_file(file.path(), &cipher).unwrap();
-    assert_eq!(encrypted_file.original_size, 20000000);
+#[cfg(test)]
+fn check_file_roundtrip(cipher: &Cipher, file: &mut tempfile::NamedTempFile) -> Codec {
+    use std::io::{Seek, SeekFrom};
+    use tempfile::NamedTempFile;
+
+    file.flush().unwrap();
+    let mut encrypted_file = encrypt_file(file.path(), cipher).unwrap();
+    let original_size = file.seek(SeekFrom::End(0)).unwrap();
+    assert_eq!(encrypted_file.original_size, original_size);
     println!(
         "encrypted size {}",
         encrypted_file.file.seek(SeekFrom::End(0)).unwrap()
     );
     encrypted_file.file.rewind().unwrap();
+    let mut header = [0u8; HEADER_SIZE];
+    encrypted_file.file.read_exact(&mut header).unwrap();
+    let codec = Codec::try_from(header[5]).unwrap();
+    encrypted_file.file.rewind().unwrap();
+
     let mut decrypted_file = NamedTempFile::new().unwrap();
-    let mut decryptor = Decryptor::new(&cipher, &mut decrypted_file);
+    let mut decryptor = Decryptor::new(cipher, &mut decrypted_file);
     io::copy(&mut encrypted_file.file, &mut decryptor).unwrap();
-    decryptor.finish().unwrap();
+    let (_, hash, size) = decryptor.finish().unwrap();
+    assert_eq!(hash, encrypted_file.hash);
+    assert_eq!(size, original_size);
     decrypted_file.flush().unwrap();
 
     file.rewind().unwrap();
@@ -436,4 +1037,206 @@ pub fn file_roundtrip() {
             break;
         }
     }
+    codec
+}
+
+#[test]
+pub fn file_roundtrip() {
+    use tempfile::NamedTempFile;
+
+    let key = Aes256SivAead::generate_key(&mut OsRng);
+    let cipher = Cipher::new(&key, false);
+
+    let mut file = NamedTempFile::new().unwrap();
+    for _ in 0..20000 {
+        let input: Vec<u8> = (0..1000).map(|_| rand::random::<u8>()).collect();
+        file.write_all(&input).unwrap();
+    }
+    assert_eq!(
+        check_file_roundtrip(&cipher, &mut file),
+        Codec::Uncompressed
+    );
+}
+
+#[test]
+pub fn compressible_file_roundtrip() {
+    use tempfile::NamedTempFile;
+
+    let key = Aes256SivAead::generate_key(&mut OsRng);
+    let cipher = Cipher::new(&key, false);
+
+    let mut file = NamedTempFile::new().unwrap();
+    for i in 0..200000 {
+        writeln!(file, "line {}", i % 1000).unwrap();
+    }
+    assert_eq!(check_file_roundtrip(&cipher, &mut file), Codec::Zstd);
+
+    let mut empty_file = NamedTempFile::new().unwrap();
+    check_file_roundtrip(&cipher, &mut empty_file);
+}
+
+#[test]
+pub fn multithreaded_compression_roundtrip() {
+    use tempfile::NamedTempFile;
+
+    let key = Aes256SivAead::generate_key(&mut OsRng);
+    let cipher = Cipher::new(&key, false);
+
+    let mut content = Vec::new();
+    let mut i = 0;
+    while (content.len() as u64) < MULTITHREADED_COMPRESSION_MIN_SIZE + 1000 {
+        writeln!(content, "line {} {}", i % 1000, rand::random::<u8>()).unwrap();
+        i += 1;
+    }
+    let mut file = NamedTempFile::new().unwrap();
+    file.write_all(&content).unwrap();
+    assert_eq!(check_file_roundtrip(&cipher, &mut file), Codec::Zstd);
+}
+
+#[test]
+pub fn padded_file_roundtrip() {
+    use std::io::Seek;
+    use tempfile::NamedTempFile;
+
+    let key = Aes256SivAead::generate_key(&mut OsRng);
+    let cipher = Cipher::new(&key, true);
+
+    for size in [0, 1000, BLOCK_SIZE, BLOCK_SIZE * 3 + 5] {
+        let mut file = NamedTempFile::new().unwrap();
+        let input: Vec<u8> = (0..size).map(|_| rand::random::<u8>()).collect();
+        file.write_all(&input).unwrap();
+        check_file_roundtrip(&cipher, &mut file);
+
+        let mut encrypted_file = encrypt_file(file.path(), &cipher).unwrap();
+        let encrypted_size = encrypted_file.file.stream_position().unwrap();
+        assert_eq!(encrypted_size, encrypted_file.encrypted_size);
+        assert_eq!(padme(encrypted_size), encrypted_size);
+    }
+}
+
+#[test]
+pub fn legacy_file_decryption() {
+    let key = Aes256SivAead::generate_key(&mut OsRng);
+    let cipher = Cipher::new(&key, false);
+
+    let content: Vec<u8> = (0..3000000u32)
+        .flat_map(|i| (i % 7).to_le_bytes())
+        .collect();
+    let mut encoder = DeflateEncoder::new(Vec::new(), CompressionOptions::high());
+    encoder.write_all(&content).unwrap();
+    let compressed = encoder.finish().unwrap();
+
+    let mut encrypted = Vec::new();
+    encrypted.write_u32::<LE>(LEGACY_MAGIC_NUMBER).unwrap();
+    for block in compressed.chunks(BLOCK_SIZE) {
+        let mut nonce = Nonce::default();
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = cipher.aead.encrypt(&nonce, block).unwrap();
+        encrypted
+            .write_u32::<LE>((nonce.len() + ciphertext.len()) as u32)
+            .unwrap();
+        encrypted.write_all(&nonce).unwrap();
+        encrypted.write_all(&ciphertext).unwrap();
+    }
+
+    let mut decryptor = Decryptor::new(&cipher, Vec::new());
+    for chunk in encrypted.chunks(4096) {
+        decryptor.write_all(chunk).unwrap();
+    }
+    let (decrypted, hash, size) = decryptor.finish().unwrap();
+    assert_eq!(size, content.len() as u64);
+    assert_eq!(hash, ContentHash::new(Sha256::digest(&content).into()));
+    assert!(decrypted == content);
+}
+
+#[test]
+pub fn block_tampering_is_detected() {
+    use std::io::Seek;
+    use tempfile::NamedTempFile;
+
+    let key = Aes256SivAead::generate_key(&mut OsRng);
+    let cipher = Cipher::new(&key, false);
+
+    let mut file = NamedTempFile::new().unwrap();
+    let input: Vec<u8> = (0..BLOCK_SIZE * 7 / 2)
+        .map(|_| rand::random::<u8>())
+        .collect();
+    file.write_all(&input).unwrap();
+    file.flush().unwrap();
+
+    let mut encrypted_file = encrypt_file(file.path(), &cipher).unwrap().file;
+    encrypted_file.rewind().unwrap();
+    let mut encrypted = Vec::new();
+    encrypted_file.read_to_end(&mut encrypted).unwrap();
+    let (header, mut rest) = encrypted.split_at(HEADER_SIZE);
+    let mut blocks = Vec::new();
+    while !rest.is_empty() {
+        let len = LE::read_u32(rest) as usize;
+        blocks.push(&rest[..4 + len]);
+        rest = &rest[4 + len..];
+    }
+    assert_eq!(blocks.len(), 4);
+
+    let decrypt = |blocks: &[&[u8]]| -> io::Result<_> {
+        let mut decryptor = Decryptor::new(&cipher, io::sink());
+        decryptor.write_all(header)?;
+        for block in blocks {
+            decryptor.write_all(block)?;
+        }
+        decryptor.finish()
+    };
+    decrypt(&blocks).unwrap();
+    decrypt(&[blocks[1], blocks[0], blocks[2], blocks[3]]).unwrap_err();
+    decrypt(&[blocks[0], blocks[1], blocks[1], blocks[2], blocks[3]]).unwrap_err();
+    decrypt(&blocks[..3]).unwrap_err();
+    decrypt(&blocks[1..]).unwrap_err();
+    decrypt(&[]).unwrap_err();
+}
+
+#[test]
+pub fn trailing_data_and_header_tampering_is_detected() {
+    use std::io::Seek;
+    use tempfile::NamedTempFile;
+
+    let key = Aes256SivAead::generate_key(&mut OsRng);
+    let mut file = NamedTempFile::new().unwrap();
+    let input: Vec<u8> = (0..5000).map(|_| rand::random::<u8>()).collect();
+    file.write_all(&input).unwrap();
+    file.flush().unwrap();
+
+    let encrypt = |cipher: &Cipher| {
+        let mut encrypted_file = encrypt_file(file.path(), cipher).unwrap().file;
+        encrypted_file.rewind().unwrap();
+        let mut encrypted = Vec::new();
+        encrypted_file.read_to_end(&mut encrypted).unwrap();
+        encrypted
+    };
+    let decrypt = |cipher: &Cipher, data: &[u8]| -> io::Result<_> {
+        let mut decryptor = Decryptor::new(cipher, io::sink());
+        decryptor.write_all(data)?;
+        decryptor.finish()
+    };
+
+    let cipher = Cipher::new(&key, false);
+    let encrypted = encrypt(&cipher);
+    decrypt(&cipher, &encrypted).unwrap();
+    let mut appended = encrypted.clone();
+    appended.extend_from_slice(&[1, 2, 3, 4, 5]);
+    decrypt(&cipher, &appended).unwrap_err();
+    let mut appended = encrypted.clone();
+    appended.extend_from_slice(&PADDING_MARKER.to_le_bytes());
+    appended.extend_from_slice(&[0; 100]);
+    decrypt(&cipher, &appended).unwrap_err();
+    let mut changed_version = encrypted.clone();
+    changed_version[4] = AUTHENTICATED_ORDER_FORMAT_VERSION;
+    decrypt(&cipher, &changed_version).unwrap_err();
+    let mut changed_codec = encrypted.clone();
+    changed_codec[5] = Codec::Zstd as u8;
+    decrypt(&cipher, &changed_codec).unwrap_err();
+
+    let padded_cipher = Cipher::new(&key, true);
+    let mut padded = encrypt(&padded_cipher);
+    decrypt(&padded_cipher, &padded).unwrap();
+    *padded.last_mut().unwrap() = 1;
+    decrypt(&padded_cipher, &padded).unwrap_err();
 }
@@ -7,14 +7,23 @@ use futures::TryStreamExt;
 use itertools::Itertools;
 use prettytable::{cell, format::FormatBuilder, row, Table};
 use rammingen_protocol::{
-    endpoints::{GetAllEntryVersions, GetDirectChildEntries, GetSources, SourceInfo},
-    ArchivePath, DateTimeUtc, EntryKind, SourceId,
+    endpoints::{
+        GetAllEntryVersions, GetDirectChildEntries, GetOperations, GetSources, PathChange,
+        SourceInfo,
+    },
+    ArchivePath, DateTimeUtc, EntryKind, OperationArgs, SourceId,
 };
 use tracing::{error, info};
 
 use crate::{
-    data::DecryptedEntryVersionData, encryption::encrypt_path, path::SanitizedLocalPath,
-    pull_updates::pull_updates, rules::Rules, upload::to_archive_path, Ctx,
+    config::MountPoint,
+    data::DecryptedEntryVersionData,
+    encryption::{decrypt_path, encrypt_path},
+    path::SanitizedLocalPath,
+    pull_updates::pull_updates,
+    rules::{ExcludeReason, ExcludeTrace, Rules},
+    upload::to_archive_path,
+    Ctx,
 };
 
 struct Sources(Vec<SourceInfo>);
@@ -52,7 +61,10 @@ pub async fn local_status(ctx: &Ctx, path: &SanitizedLocalPath) -> Result<()> {
 
     if let Some((archive_path, rules)) = to_archive_path(path, &mut mount_points)? {
         if rules.matches(path)? {
-            info!("this path is ignored according to the configured exclude rules");
+            info!(
+                "this path is ignored according to the configured exclude rules \
+                (use `explain-exclude` to see which rule matched)"
+            );
         } else {
             info!("archive path: {}", archive_path);
             let encrypted = encrypt_path(&archive_path, &ctx.cipher)?;
@@ -73,6 +85,90 @@ pub async fn local_status(ctx: &Ctx, path: &SanitizedLocalPath) -> Result<()> {
     Ok(())
 }
 
+pub fn explain_exclude(ctx: &Ctx, path: &SanitizedLocalPath) -> Result<()> {
+    let Some(mount_point) = ctx
+        .config
+        .mount_points
+        .iter()
+        .find(|mount_point| path.as_path().starts_with(&mount_point.local_path))
+    else {
+        info!("this path is not inside any of the configured mount points");
+        return Ok(());
+    };
+    let mut rules = Rules::new(
+        &[&ctx.config.always_exclude, &mount_point.exclude],
+        mount_point.local_path.clone(),
+    );
+    if let Some(trace) = rules.explain(path)? {
+        print_exclude_trace(mount_point, path, &trace)?;
+    } else if fs_err::symlink_metadata(path).map_or(false, |m| m.is_dir()) {
+        let mut num_excluded = 0;
+        explain_exclude_in_dir(mount_point, &mut rules, path, &mut num_excluded)?;
+        if num_excluded == 0 {
+            info!("no paths are excluded in this directory");
+        }
+    } else {
+        info!("this path is not excluded");
+    }
+    Ok(())
+}
+
+/// Prints reasons for all excluded paths in `dir`. Nested paths of excluded directories
+/// are not printed.
+fn explain_exclude_in_dir(
+    mount_point: &MountPoint,
+    rules: &mut Rules,
+    dir: &SanitizedLocalPath,
+    num_excluded: &mut u64,
+) -> Result<()> {
+    for entry in fs_err::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+        let path = dir.join(file_name)?;
+        if let Some(trace) = rules.explain(&path)? {
+            print_exclude_trace(mount_point, &path, &trace)?;
+            *num_excluded += 1;
+        } else if entry.file_type()?.is_dir() {
+            explain_exclude_in_dir(mount_point, rules, &path, num_excluded)?;
+        }
+    }
+    Ok(())
+}
+
+fn print_exclude_trace(
+    mount_point: &MountPoint,
+    path: &SanitizedLocalPath,
+    trace: &ExcludeTrace,
+) -> Result<()> {
+    let reason = match &trace.reason {
+        ExcludeReason::Internal => "internal files of rammingen".to_string(),
+        ExcludeReason::Rule { list, index, rule } => {
+            // Lists are passed to `Rules::new` in `explain_exclude`.
+            let origin = if *list == 0 {
+                format!("always_exclude[{index}]")
+            } else {
+                format!("exclude[{index}] of mount point {}", mount_point.local_path)
+            };
+            format!("rule {} from {}", serde_json::to_string(rule)?, origin)
+        }
+        ExcludeReason::IgnoreFile { file, pattern } => {
+            format!("pattern {:?} from {}", pattern, file)
+        }
+    };
+    if &trace.path == path {
+        info!("{} is excluded by {}", path, reason);
+    } else {
+        info!(
+            "{} is excluded because its parent {} is excluded by {}",
+            path, trace.path, reason
+        );
+    }
+    Ok(())
+}
+
 pub async fn ls(ctx: &Ctx, path: &ArchivePath, show_deleted: bool) -> Result<()> {
     pull_updates(ctx).await?;
     let sources = get_sources(ctx).await?;
@@ -169,7 +265,7 @@ pub async fn ls(ctx: &Ctx, path: &ArchivePath, show_deleted: bool) -> Result<()>
 
 pub const DATE_TIME_FORMAT: &str = "%Y-%m-%d_%H:%M:%S";
 
-fn pretty_time(value: DateTimeUtc) -> impl Display {
+pub fn pretty_time(value: DateTimeUtc) -> impl Display {
     let mut local = DateTime::<Local>::from(value);
     if local.nanosecond() != 0 {
         local = local.trunc_subsecs(0) + chrono::Duration::seconds(1);
@@ -201,6 +297,51 @@ fn pretty_status(data: &DecryptedEntryVersionData) -> Result<String> {
     Ok(text)
 }
 
+/// Prints changes that would be made by a bulk action.
+pub fn print_path_changes(ctx: &Ctx, changes: Vec<PathChange>) -> Result<()> {
+    if changes.is_empty() {
+        info!("No paths would be changed.");
+        return Ok(());
+    }
+    let num_changes = changes.len();
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().column_separator(' ').build());
+    table.add_row(row!["Old", "New", "Path"]);
+    for change in changes {
+        let new = DecryptedEntryVersionData::new(ctx, change.new)?;
+        let old = change
+            .old
+            .map(|old| DecryptedEntryVersionData::new(ctx, old))
+            .transpose()?;
+        let old_status = if let Some(old) = &old {
+            pretty_status(old)?
+        } else {
+            "-".to_string()
+        };
+        let mut new_status = pretty_status(&new)?;
+        let old_hash = old
+            .as_ref()
+            .and_then(|old| old.content.as_ref())
+            .map(|content| &content.hash);
+        let new_hash = new.content.as_ref().map(|content| &content.hash);
+        if old_hash.is_some() && new_hash.is_some() && old_hash != new_hash {
+            new_status.push_str(" (new content)");
+        }
+        table.add_row(row![old_status, new_status, new.path]);
+        if table.len() > 50 {
+            info!("{table}");
+            table = Table::new();
+            table.set_format(FormatBuilder::new().column_separator(' ').build());
+        }
+    }
+    info!("{table}");
+    info!(
+        "{} paths would be changed (dry run, nothing was changed)",
+        num_changes
+    );
+    Ok(())
+}
+
 pub fn pretty_size(size: u64) -> impl Display {
     Byte::from_bytes(size)
         .get_appropriate_unit(false)
@@ -248,3 +389,103 @@ pub async fn list_versions(ctx: &Ctx, path: &ArchivePath, recursive: bool) -> Re
     info!("{table}");
     Ok(())
 }
+
+/// Returns true if `a` and `b` are the same path or one of them is nested in the other.
+fn is_related_path(a: &ArchivePath, b: &ArchivePath) -> bool {
+    a == b || a.strip_prefix(b).is_some() || b.strip_prefix(a).is_some()
+}
+
+pub async fn list_operations(
+    ctx: &Ctx,
+    path: Option<&ArchivePath>,
+    since: Option<DateTimeUtc>,
+) -> Result<()> {
+    let sources = get_sources(ctx).await?;
+    let mut stream = ctx.client.stream(&GetOperations { since });
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().column_separator(' ').build());
+    table.add_row(row![
+        "Id",
+        "Recorded",
+        "Source",
+        "Operation",
+        "Affected",
+        "Paths"
+    ]);
+    while let Some(operation) = stream.try_next().await? {
+        let (paths, description) = match &operation.args {
+            OperationArgs::AddVersions { paths } => {
+                let paths = paths
+                    .iter()
+                    .map(|path| decrypt_path(path, &ctx.cipher))
+                    .collect::<Result<Vec<_>>>()?;
+                let mut description = paths.iter().take(1).join("");
+                if paths.len() > 1 {
+                    description.push_str(&format!(" (and {} more)", paths.len() - 1));
+                }
+                (paths, description)
+            }
+            OperationArgs::MovePath { old_path, new_path } => {
+                let old_path = decrypt_path(old_path, &ctx.cipher)?;
+                let new_path = decrypt_path(new_path, &ctx.cipher)?;
+                let description = format!("{old_path} -> {new_path}");
+                (vec![old_path, new_path], description)
+            }
+            OperationArgs::CopyPath {
+                old_path,
+                new_path,
+                recorded_at,
+            } => {
+                let old_path = decrypt_path(old_path, &ctx.cipher)?;
+                let new_path = decrypt_path(new_path, &ctx.cipher)?;
+                let mut description = format!("{old_path} -> {new_path}");
+                if let Some(recorded_at) = recorded_at {
+                    description.push_str(&format!(" (version at {})", pretty_time(*recorded_at)));
+                }
+                (vec![old_path, new_path], description)
+            }
+            OperationArgs::PurgePath { path } => {
+                let path = decrypt_path(path, &ctx.cipher)?;
+                let description = path.to_string();
+                (vec![path], description)
+            }
+            OperationArgs::RemovePath { path } => {
+                let path = decrypt_path(path, &ctx.cipher)?;
+                let description = path.to_string();
+                (vec![path], description)
+            }
+            OperationArgs::ResetVersion { path, recorded_at } => {
+                let path = decrypt_path(path, &ctx.cipher)?;
+                let description = format!("{path} to {}", pretty_time(*recorded_at));
+                (vec![path], description)
+            }
+            OperationArgs::UndoOperation { id, force } => {
+                let mut description = format!("operation {id}");
+                if *force {
+                    description.push_str(" (forced)");
+                }
+                (Vec::new(), description)
+            }
+        };
+        if let Some(filter) = path {
+            if !paths.iter().any(|path| is_related_path(path, filter)) {
+                continue;
+            }
+        }
+        table.add_row(row![
+            operation.id,
+            pretty_time(operation.recorded_at),
+            sources.format(operation.source_id),
+            operation.args.endpoint(),
+            operation.affected_paths,
+            description,
+        ]);
+        if table.len() > 50 {
+            info!("{table}");
+            table = Table::new();
+            table.set_format(FormatBuilder::new().column_separator(' ').build());
+        }
+    }
+    info!("{table}");
+    Ok(())
+}
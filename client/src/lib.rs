@@ -1,5 +1,6 @@
 #![allow(clippy::collapsible_if)]
 
+mod adopt;
 pub mod cli;
 mod client;
 pub mod config;
@@ -7,54 +8,70 @@ mod counters;
 mod data;
 mod db;
 mod download;
-mod encryption;
+pub mod encryption;
 mod info;
+mod mass_change;
+mod mount_point;
 pub mod path;
 mod pull_updates;
+mod rename;
 pub mod rules;
 mod sync;
 pub mod term;
+mod trash;
 mod upload;
 
 use crate::{
+    adopt::adopt,
     info::{local_status, ls},
-    pull_updates::pull_updates,
-    upload::upload,
+    pull_updates::{pull_updates, wait_for_updates},
+    upload::{upload, upload_stream},
 };
-use aes_siv::{Aes256SivAead, KeyInit};
 use anyhow::{anyhow, bail, Result};
 use cli::Cli;
-use client::Client;
+use client::{Client, RETRY_INTERVAL};
 use config::Config;
 use counters::Counters;
 use derivative::Derivative;
-use download::{download_latest, download_version};
-use encryption::encrypt_path;
-use info::{list_versions, pretty_size};
+use download::{cat, download_latest, download_version};
+use encryption::{derive_key, encrypt_path, Cipher};
+use info::{explain_exclude, list_operations, list_versions, pretty_size, print_path_changes};
+use mass_change::MassChangeLimitExceeded;
+use mount_point::{move_mount_point, remove_mount_point};
 use rammingen_protocol::{
-    endpoints::{CheckIntegrity, GetServerStatus, MovePath, RemovePath, ResetVersion},
+    endpoints::{
+        CheckIntegrity, CopyPath, GetServerStatus, MovePath, PreviewMovePath, PreviewRemovePath,
+        PreviewResetVersion, PurgePath, RemovePath, ResetVersion, UndoOperation,
+    },
     util::log_writer,
 };
 use rules::Rules;
 use std::fs::Metadata;
 use std::{
+    cmp::min,
     collections::HashSet,
     path::PathBuf,
     sync::{Arc, Mutex},
+    time::Duration,
 };
 use sync::sync;
 use term::TermLayer;
-use tracing::info;
+use tokio::time::sleep;
+use tracing::{info, warn};
 use tracing_subscriber::{
     prelude::__tracing_subscriber_SubscriberExt, util::SubscriberInitExt, EnvFilter,
 };
+use trash::{list_trash, restore_from_trash};
+
+/// Upper limit of the delay between failed syncs in watch mode.
+const MAX_WATCH_RETRY_INTERVAL: Duration = Duration::from_secs(600);
 
 #[derive(Derivative)]
 pub struct Ctx {
     pub config: Config,
     pub client: Client,
     #[derivative(Debug = "ignore")]
-    pub cipher: Aes256SivAead,
+    pub cipher: Cipher,
     pub db: crate::db::Db,
     pub counters: Counters,
 }
@@ -66,11 +83,21 @@ pub async fn run(cli: Cli, config: Config) -> Result<()> {
         let data_dir = dirs::data_dir().ok_or_else(|| anyhow!("cannot find config dir"))?;
         data_dir.join("rammingen.db")
     };
+    let local_db_cipher = Cipher::new(
+        &derive_key(config.encryption_key.get(), "rammingen local db"),
+        false,
+    );
+    let db = crate::db::Db::open(
+        &local_db_path,
+        config.encrypt_local_db.then(|| local_db_cipher.clone()),
+        &local_db_cipher,
+    )?;
+    db.check_privacy_padding(config.privacy_padding, cli.confirm_privacy_padding)?;
     let ctx = Arc::new(Ctx {
         client: Client::new(config.server_url.clone(), &config.access_token),
-        cipher: Aes256SivAead::new(config.encryption_key.get()),
+        cipher: Cipher::new(config.encryption_key.get(), config.privacy_padding),
         config,
-        db: crate::db::Db::open(&local_db_path)?,
+        db,
         counters: Counters::default(),
     });
 
@@ -83,10 +110,33 @@ pub async fn run(cli: Cli, config: Config) -> Result<()> {
 async fn handle_command(cli: Cli, ctx: &Arc<Ctx>) -> Result<()> {
     match cli.command {
         cli::Command::DryRun => {
-            sync(ctx, true).await?;
+            sync(ctx, true, false).await?;
+        }
+        cli::Command::Sync { force } => {
+            sync(ctx, false, force).await?;
+        }
+        cli::Command::Watch => {
+            let mut retry_interval = RETRY_INTERVAL;
+            loop {
+                match sync(ctx, false, false).await {
+                    Ok(()) => retry_interval = RETRY_INTERVAL,
+                    // Retrying won't help, the user has to review the changes.
+                    Err(err) if err.is::<MassChangeLimitExceeded>() => return Err(err),
+                    Err(err) => {
+                        warn!(?err, "sync failed, will retry in {:?}", retry_interval);
+                        sleep(retry_interval).await;
+                        retry_interval = min(retry_interval * 2, MAX_WATCH_RETRY_INTERVAL);
+                        continue;
+                    }
+                }
+                wait_for_updates(ctx).await?;
+            }
         }
-        cli::Command::Sync => {
-            sync(ctx, false).await?;
+        cli::Command::Adopt {
+            replace_mismatched,
+            dry_run,
+        } => {
+            adopt(ctx, replace_mismatched, dry_run).await?;
         }
         cli::Command::Upload {
             local_path,
@@ -103,6 +153,9 @@ async fn handle_command(cli: Cli, ctx: &Arc<Ctx>) -> Result<()> {
             )
             .await?;
         }
+        cli::Command::UploadStream { archive_path, mode } => {
+            upload_stream(ctx, &archive_path, mode.map(|mode| mode.0)).await?
+        }
         cli::Command::Download {
             archive_path,
             local_path,
@@ -126,43 +179,133 @@ async fn handle_command(cli: Cli, ctx: &Arc<Ctx>) -> Result<()> {
                 bail!("no matching entries found");
             }
         }
+        cli::Command::Cat { archive_path, at } => {
+            cat(ctx, &archive_path, at.map(|at| at.0)).await?
+        }
         cli::Command::LocalStatus { path } => local_status(ctx, &path).await?,
+        cli::Command::ExplainExclude { path } => explain_exclude(ctx, &path)?,
         cli::Command::Ls { path, deleted } => ls(ctx, &path, deleted).await?,
         cli::Command::Reset {
             archive_path,
             version,
+            dry_run,
         } => {
-            let stats = ctx
-                .client
-                .request(&ResetVersion {
-                    path: encrypt_path(&archive_path, &ctx.cipher)?,
-                    recorded_at: version.into(),
-                })
-                .await?;
-            info!("{:?}", stats);
+            let request = ResetVersion {
+                path: encrypt_path(&archive_path, &ctx.cipher)?,
+                recorded_at: version.into(),
+            };
+            if dry_run {
+                let changes = ctx.client.request(&PreviewResetVersion(request)).await?;
+                print_path_changes(ctx, changes)?;
+            } else {
+                let stats = ctx.client.request(&request).await?;
+                info!("{:?}", stats);
+            }
+        }
+        cli::Command::Move {
+            old_path,
+            new_path,
+            dry_run,
+        } => {
+            let request = MovePath {
+                old_path: encrypt_path(&old_path, &ctx.cipher)?,
+                new_path: encrypt_path(&new_path, &ctx.cipher)?,
+            };
+            if dry_run {
+                let changes = ctx.client.request(&PreviewMovePath(request)).await?;
+                print_path_changes(ctx, changes)?;
+            } else {
+                let stats = ctx.client.request(&request).await?;
+                info!("{stats:?}");
+            }
         }
-        cli::Command::Move { old_path, new_path } => {
+        cli::Command::Copy {
+            old_path,
+            new_path,
+            at,
+        } => {
             let stats = ctx
                 .client
-                .request(&MovePath {
+                .request(&CopyPath {
                     old_path: encrypt_path(&old_path, &ctx.cipher)?,
                     new_path: encrypt_path(&new_path, &ctx.cipher)?,
+                    recorded_at: at.map(|at| at.0),
                 })
                 .await?;
             info!("{stats:?}");
         }
-        cli::Command::Remove { archive_path } => {
+        cli::Command::Remove {
+            archive_path,
+            dry_run,
+        } => {
+            let request = RemovePath {
+                path: encrypt_path(&archive_path, &ctx.cipher)?,
+            };
+            if dry_run {
+                let changes = ctx.client.request(&PreviewRemovePath(request)).await?;
+                print_path_changes(ctx, changes)?;
+            } else {
+                let stats = ctx.client.request(&request).await?;
+                info!("{:?}", stats);
+            }
+        }
+        cli::Command::Purge { archive_path, yes } => {
+            if !yes {
+                info!(
+                    "All versions of {} and its nested paths will be permanently deleted \
+                    from the server. Type the path again to confirm:",
+                    archive_path
+                );
+                let mut input = String::new();
+                std::io::stdin().read_line(&mut input)?;
+                if input.trim() != archive_path.to_string() {
+                    bail!("purge was not confirmed");
+                }
+            }
             let stats = ctx
                 .client
-                .request(&RemovePath {
+                .request(&PurgePath {
                     path: encrypt_path(&archive_path, &ctx.cipher)?,
+                    confirm: true,
                 })
                 .await?;
-            info!("{:?}", stats);
+            info!("{stats:?}");
         }
         cli::Command::History { path, recursive } => {
             list_versions(ctx, &path, recursive).await?;
         }
+        cli::Command::Operations { path, since } => {
+            list_operations(ctx, path.as_ref(), since.map(|since| since.0)).await?;
+        }
+        cli::Command::Undo {
+            operation_id,
+            force,
+        } => {
+            let stats = ctx
+                .client
+                .request(&UndoOperation {
+                    id: operation_id.into(),
+                    force,
+                })
+                .await?;
+            info!("{stats:?}");
+        }
+        cli::Command::MountPoint { command } => {
+            let config_path = cli::config_path(cli.config.as_deref())?;
+            match command {
+                cli::MountPointCommand::Move {
+                    old_local_path,
+                    new_local_path,
+                } => move_mount_point(ctx, &config_path, &old_local_path, &new_local_path)?,
+                cli::MountPointCommand::Remove { local_path } => {
+                    remove_mount_point(ctx, &config_path, &local_path)?
+                }
+            }
+        }
+        cli::Command::Trash { command } => match command {
+            cli::TrashCommand::List => list_trash(ctx)?,
+            cli::TrashCommand::Restore { id } => restore_from_trash(&ctx.config.mount_points, &id)?,
+        },
         cli::Command::Status => {
             let status = ctx.client.request(&GetServerStatus).await?;
             info!(
@@ -191,6 +334,18 @@ pub fn unix_mode(_metadata: &Metadata) -> Option<u32> {
     None
 }
 
+#[cfg(target_family = "unix")]
+pub fn inode(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.ino())
+}
+
+#[cfg(not(target_family = "unix"))]
+pub fn inode(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
 pub fn setup_logger(log_file: Option<PathBuf>, log_filter: String) -> Result<()> {
     // Defaults to stdout if `data_dir()` fails.
     let log_file = log_file.or_else(|| dirs::data_dir().map(|dir| dir.join("rammingen.log")));
@@ -1,8 +1,8 @@
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
 use clap::Parser;
 use rammingen::{
-    cli::{Cli, Command},
+    cli::{config_path, Cli, Command},
     config::{Config, EncryptionKey},
     setup_logger,
 };
@@ -23,12 +23,12 @@ async fn try_main() -> Result<()> {
         return Ok(());
     }
 
-    let config_path = if let Some(config) = &cli.config {
-        config.clone()
-    } else {
-        let config_dir = dirs::config_dir().ok_or_else(|| anyhow!("cannot find config dir"))?;
-        config_dir.join("rammingen.conf")
-    };
+    if matches!(cli.command, Command::Cat { .. }) {
+        // Stdout is used for file content.
+        rammingen::term::use_stderr();
+    }
+
+    let config_path = config_path(cli.config.as_deref())?;
     let config: Config = json5::from_str(&fs_err::read_to_string(config_path)?)?;
     setup_logger(config.log_file.clone(), config.log_filter.clone())?;
     if let Err(err) = rammingen::run(cli, config).await {
@@ -0,0 +1,201 @@
+//! Detection of suspiciously large local changes before sync propagates them to the server.
+
+use std::{collections::HashSet, fmt, io::ErrorKind};
+
+use anyhow::Result;
+use fs_err as fs;
+use rammingen_protocol::{DateTimeUtc, EntryKind};
+use tracing::warn;
+
+use crate::{
+    config::MountPoint, path::SanitizedLocalPath, rename::Rename, rules::Rules, term::set_status,
+    unix_mode, Ctx,
+};
+
+/// Returned by [`check_mass_changes`] when local changes exceed the limits.
+///
+/// Unlike network errors, it doesn't go away on retry, so watch mode stops on it.
+#[derive(Debug)]
+pub struct MassChangeLimitExceeded;
+
+impl fmt::Display for MassChangeLimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sync stopped because local changes exceed `mass_change_limits` \
+            (use `sync --force` if these changes are expected)"
+        )
+    }
+}
+
+impl std::error::Error for MassChangeLimitExceeded {}
+
+/// Changes of local entries of a mount point since the last sync.
+#[derive(Debug, Default)]
+struct MountPointChanges {
+    /// Number of local entries recorded on the last sync.
+    total: u64,
+    deleted: u64,
+    /// Entries with changed kind, modification time or permissions, and renamed entries.
+    /// Content of some of these files may be unchanged.
+    modified: u64,
+}
+
+/// Returns true if the mount point root doesn't exist or is empty,
+/// but it had nested entries on the last sync.
+///
+/// This usually means that the drive is not mounted, so the mount point must not be synced.
+pub fn is_unexpectedly_empty(ctx: &Ctx, mount_point: &MountPoint) -> Result<bool> {
+    let root = &mount_point.local_path;
+    match fs::symlink_metadata(root) {
+        Ok(metadata) => {
+            // A mount point can be a single file, it never has nested entries.
+            if !metadata.is_dir() || fs::read_dir(root)?.next().is_some() {
+                return Ok(false);
+            }
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+    ctx.db.has_nested_local_entries(root)
+}
+
+/// Checks the number of deleted and modified local entries in each mount point against
+/// `mass_change_limits` from the config. Fails with a report if any limit is exceeded,
+/// or only reports it in dry run mode.
+///
+/// `renames` must not be recorded yet. Renamed entries and their nested entries
+/// count as modified.
+pub fn check_mass_changes(
+    ctx: &Ctx,
+    mount_points: &mut [(&MountPoint, Rules)],
+    renames: &[Rename],
+    dry_run: bool,
+) -> Result<()> {
+    let limits = &ctx.config.mass_change_limits;
+    if limits.is_empty() {
+        return Ok(());
+    }
+    let _status = set_status("Checking for mass changes");
+    let mut all_changes: Vec<MountPointChanges> =
+        mount_points.iter().map(|_| Default::default()).collect();
+    let renamed_paths: HashSet<&SanitizedLocalPath> = renames
+        .iter()
+        .map(|rename| &rename.old_local_path)
+        .collect();
+    for entry in ctx.db.get_all_local_entries() {
+        let (local_path, data) = entry?;
+        let Some(index) = mount_points
+            .iter()
+            .position(|(mount_point, _)| local_path.as_path().starts_with(&mount_point.local_path))
+        else {
+            continue;
+        };
+        if mount_points[index]
+            .1
+            .matches_with_kind(&local_path, data.kind)?
+        {
+            continue;
+        }
+        let changes = &mut all_changes[index];
+        changes.total += 1;
+        let metadata = match fs::symlink_metadata(&local_path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                if is_renamed(&local_path, &renamed_paths)? {
+                    changes.modified += 1;
+                } else {
+                    changes.deleted += 1;
+                }
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if metadata.is_symlink() {
+            // Symlinks are skipped on upload, so the entry will be recorded as deleted.
+            changes.deleted += 1;
+            continue;
+        }
+        let kind = if metadata.is_dir() {
+            EntryKind::Directory
+        } else {
+            EntryKind::File
+        };
+        let modified = kind != data.kind
+            || (kind == EntryKind::File && {
+                let modified_at = DateTimeUtc::from(metadata.modified()?);
+                data.content.as_ref().map_or(true, |content| {
+                    content.modified_at != modified_at || content.unix_mode != unix_mode(&metadata)
+                })
+            });
+        if modified {
+            changes.modified += 1;
+        }
+    }
+
+    let mut exceeded = false;
+    for ((mount_point, _), changes) in mount_points.iter().zip(&all_changes) {
+        let too_many_deleted = exceeds(
+            changes.deleted,
+            changes.total,
+            limits.max_deleted_entries,
+            limits.max_deleted_percent,
+        );
+        let too_many_modified = exceeds(
+            changes.modified,
+            changes.total,
+            limits.max_modified_entries,
+            limits.max_modified_percent,
+        );
+        if too_many_deleted || too_many_modified {
+            exceeded = true;
+            warn!(
+                "Too many changes in {}: {} of {} entries deleted, {} modified",
+                mount_point.local_path, changes.deleted, changes.total, changes.modified,
+            );
+        }
+    }
+    if exceeded && !dry_run {
+        return Err(MassChangeLimitExceeded.into());
+    }
+    Ok(())
+}
+
+/// Returns true if `path` or any of its parents is in `renamed_paths`.
+fn is_renamed(
+    path: &SanitizedLocalPath,
+    renamed_paths: &HashSet<&SanitizedLocalPath>,
+) -> Result<bool> {
+    if renamed_paths.is_empty() {
+        return Ok(false);
+    }
+    let mut current = Some(path.clone());
+    while let Some(path) = current {
+        if renamed_paths.contains(&path) {
+            return Ok(true);
+        }
+        current = path.parent()?;
+    }
+    Ok(false)
+}
+
+fn exceeds(count: u64, total: u64, max_count: Option<u64>, max_percent: Option<f64>) -> bool {
+    if max_count.map_or(false, |max_count| count > max_count) {
+        return true;
+    }
+    total > 0
+        && max_percent.map_or(false, |max_percent| {
+            count as f64 * 100.0 / total as f64 > max_percent
+        })
+}
+
+#[test]
+fn exceeds_limits() {
+    assert!(!exceeds(0, 0, Some(0), Some(0.0)));
+    assert!(!exceeds(10, 100, Some(10), None));
+    assert!(exceeds(11, 100, Some(10), None));
+    assert!(!exceeds(50, 100, None, Some(50.0)));
+    assert!(exceeds(51, 100, None, Some(50.0)));
+    assert!(exceeds(51, 100, Some(1000), Some(50.0)));
+    assert!(!exceeds(1000, 1000, None, None));
+}
@@ -0,0 +1,67 @@
+//! Changing mount points without losing or corrupting the sync state in the local db.
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use tracing::info;
+
+use crate::{path::SanitizedLocalPath, Ctx};
+
+/// Changes local path of a mount point in the local db and in the config file at `config_path`.
+///
+/// Running it again after a failure is safe: the local db is updated atomically
+/// before the config is saved, and moving entries that were already moved does nothing.
+pub fn move_mount_point(
+    ctx: &Ctx,
+    config_path: &Path,
+    old_local_path: &SanitizedLocalPath,
+    new_local_path: &SanitizedLocalPath,
+) -> Result<()> {
+    let mut config = ctx.config.clone();
+    if config.mount_points.iter().any(|mount_point| {
+        &mount_point.local_path != old_local_path
+            && (mount_point.local_path.as_path().starts_with(new_local_path)
+                || new_local_path
+                    .as_path()
+                    .starts_with(&mount_point.local_path))
+    }) {
+        bail!("{} overlaps with an existing mount point", new_local_path);
+    }
+    let Some(mount_point) = config
+        .mount_points
+        .iter_mut()
+        .find(|mount_point| &mount_point.local_path == old_local_path)
+    else {
+        bail!("no mount point with local path {}", old_local_path);
+    };
+    ctx.db.move_local_entries(old_local_path, new_local_path)?;
+    mount_point.local_path = new_local_path.clone();
+    config.save(config_path)?;
+    info!("Moved mount point {} to {}", old_local_path, new_local_path);
+    Ok(())
+}
+
+/// Removes a mount point from the config file at `config_path`
+/// and removes its local entries from the local db.
+pub fn remove_mount_point(
+    ctx: &Ctx,
+    config_path: &Path,
+    local_path: &SanitizedLocalPath,
+) -> Result<()> {
+    let mut config = ctx.config.clone();
+    let Some(index) = config
+        .mount_points
+        .iter()
+        .position(|mount_point| &mount_point.local_path == local_path)
+    else {
+        bail!("no mount point with local path {}", local_path);
+    };
+    let removed = ctx.db.remove_local_entries(local_path)?;
+    config.mount_points.remove(index);
+    config.save(config_path)?;
+    info!(
+        "Removed mount point {} ({} local entries forgotten)",
+        local_path, removed
+    );
+    Ok(())
+}
@@ -1,15 +1,54 @@
-use std::cmp::max;
+use std::{cmp::max, time::Duration};
 
 use anyhow::Result;
-use futures::TryStreamExt;
-use rammingen_protocol::endpoints::GetNewEntries;
+use futures::{Stream, TryStreamExt};
+use rammingen_protocol::{
+    endpoints::{GetNewEntries, WaitForNewEntries},
+    Entry, EntryUpdateNumber,
+};
+use tokio::time::sleep;
+use tracing::warn;
 
-use crate::{data::DecryptedEntryVersionData, term::set_status, Ctx};
+use crate::{client::RETRY_INTERVAL, data::DecryptedEntryVersionData, term::set_status, Ctx};
+
+/// Timeout of a single `WaitForNewEntries` request. It must be lower than
+/// the client's timeout of receiving data from a stream.
+const WAIT_FOR_UPDATES_TIMEOUT: Duration = Duration::from_secs(20);
 
 pub async fn pull_updates(ctx: &Ctx) -> Result<()> {
     let _status = set_status("Pulling updates from server");
-    let mut last_update_number = ctx.db.last_entry_update_number()?;
-    let mut stream = ctx.client.stream(&GetNewEntries { last_update_number });
+    let last_update_number = ctx.db.last_entry_update_number()?;
+    let stream = ctx.client.stream(&GetNewEntries { last_update_number });
+    save_updates(ctx, stream, last_update_number).await?;
+    Ok(())
+}
+
+/// Waits until there are new entries on the server and saves them to the local db.
+pub async fn wait_for_updates(ctx: &Ctx) -> Result<()> {
+    let _status = set_status("Waiting for updates from server");
+    loop {
+        let last_update_number = ctx.db.last_entry_update_number()?;
+        let stream = ctx.client.stream(&WaitForNewEntries {
+            last_update_number,
+            timeout: WAIT_FOR_UPDATES_TIMEOUT,
+        });
+        match save_updates(ctx, stream, last_update_number).await {
+            Ok(0) => {}
+            Ok(_) => return Ok(()),
+            Err(err) => {
+                warn!(?err, "failed to wait for updates, will retry");
+                sleep(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+/// Saves received entries to the local db. Returns the number of received entries.
+async fn save_updates(
+    ctx: &Ctx,
+    mut stream: impl Stream<Item = Result<Entry>> + Unpin,
+    mut last_update_number: EntryUpdateNumber,
+) -> Result<usize> {
     let mut decrypted = Vec::new();
     while let Some(update) = stream.try_next().await? {
         decrypted.push(DecryptedEntryVersionData::new(ctx, update.data)?);
@@ -17,5 +56,5 @@ pub async fn pull_updates(ctx: &Ctx) -> Result<()> {
     }
     ctx.db
         .update_archive_entries(&decrypted, last_update_number)?;
-    Ok(())
+    Ok(decrypted.len())
 }
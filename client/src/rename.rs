@@ -0,0 +1,348 @@
+//! Detection of local renames, so that they are recorded as moves on the server
+//! instead of a deletion and a new upload, and the history of the entry follows it.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::Metadata,
+    io::ErrorKind,
+    sync::atomic::Ordering,
+};
+
+use anyhow::{anyhow, Result};
+use fs_err as fs;
+use rammingen_protocol::{endpoints::MovePath, ArchivePath, ContentHash, DateTimeUtc, EntryKind};
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+use crate::{
+    config::MountPoint,
+    data::LocalEntryInfo,
+    encryption::{encrypt_path, hash_file},
+    inode,
+    path::SanitizedLocalPath,
+    rules::Rules,
+    term::set_status,
+    upload::to_archive_path,
+    Ctx,
+};
+
+/// A local entry that was recorded on the last sync but doesn't exist anymore.
+struct DisappearedEntry {
+    local_path: SanitizedLocalPath,
+    archive_path: ArchivePath,
+    info: LocalEntryInfo,
+    inode: Option<u64>,
+    matched: bool,
+}
+
+impl DisappearedEntry {
+    /// Directories without a recorded inode can't be matched.
+    fn can_match(&self) -> bool {
+        self.inode.is_some() || self.info.kind == EntryKind::File
+    }
+}
+
+pub struct Rename {
+    pub old_local_path: SanitizedLocalPath,
+    old_archive_path: ArchivePath,
+    new_local_path: SanitizedLocalPath,
+    new_archive_path: ArchivePath,
+}
+
+#[derive(Default)]
+struct Candidates {
+    entries: Vec<DisappearedEntry>,
+    by_inode: HashMap<u64, usize>,
+    by_hash: HashMap<ContentHash, Vec<usize>>,
+    file_sizes: HashSet<u64>,
+    /// Names of recorded direct children of disappeared directories.
+    child_names: HashMap<SanitizedLocalPath, HashSet<String>>,
+    /// Number of entries that are not matched yet but can still be matched.
+    num_unmatched: usize,
+}
+
+impl Candidates {
+    fn add(&mut self, entry: DisappearedEntry) -> Result<()> {
+        let index = self.entries.len();
+        if let (Some(parent), Some(name)) =
+            (entry.local_path.parent()?, entry.local_path.file_name())
+        {
+            self.child_names
+                .entry(parent)
+                .or_default()
+                .insert(name.to_string());
+        }
+        if entry.can_match() {
+            self.num_unmatched += 1;
+        }
+        if let Some(inode) = entry.inode {
+            self.by_inode.insert(inode, index);
+        }
+        if let Some(content) = &entry.info.content {
+            self.by_hash
+                .entry(content.hash.clone())
+                .or_default()
+                .push(index);
+            self.file_sizes.insert(content.original_size);
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Returns true if all disappeared entries that can be matched are already matched,
+    /// so there is no need to look at the remaining new paths.
+    fn is_exhausted(&self) -> bool {
+        self.num_unmatched == 0
+    }
+
+    /// Marks the entry and, if it's a directory, its nested entries as matched.
+    /// Nested entries are moved along with the directory.
+    fn mark_matched(&mut self, index: usize) {
+        let path = self.entries[index].local_path.clone();
+        for entry in &mut self.entries {
+            if !entry.matched && entry.local_path.as_path().starts_with(path.as_path()) {
+                entry.matched = true;
+                if entry.can_match() {
+                    self.num_unmatched -= 1;
+                }
+            }
+        }
+    }
+
+    /// Returns true if the directory at `local_path` contains at least one of the entries
+    /// recorded in the disappeared directory, or both of them are empty.
+    fn has_common_children(&self, index: usize, local_path: &SanitizedLocalPath) -> Result<bool> {
+        let old_names = self.child_names.get(&self.entries[index].local_path);
+        let mut is_empty = true;
+        for entry in fs::read_dir(local_path)? {
+            let entry = entry?;
+            is_empty = false;
+            if let (Some(old_names), Some(name)) = (old_names, entry.file_name().to_str()) {
+                if old_names.contains(name) {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(is_empty && old_names.is_none())
+    }
+
+    /// Returns the disappeared entry that was renamed to `local_path`.
+    ///
+    /// Directories are matched by inode, and at least one of the recorded children must still
+    /// be in the directory, because inodes of deleted directories are reused.
+    /// Files are matched by content hash, and inode is used to choose between files
+    /// with the same content. Hashing is skipped if inode, size and modification time
+    /// are unchanged.
+    fn find_match(
+        &mut self,
+        local_path: &SanitizedLocalPath,
+        metadata: &Metadata,
+    ) -> Result<Option<usize>> {
+        let kind = if metadata.is_dir() {
+            EntryKind::Directory
+        } else {
+            EntryKind::File
+        };
+        let same_inode = inode(metadata)
+            .and_then(|inode| self.by_inode.get(&inode).copied())
+            .filter(|&index| {
+                let entry = &self.entries[index];
+                !entry.matched && entry.info.kind == kind
+            });
+        let index = match kind {
+            EntryKind::Directory => match same_inode {
+                Some(index) if self.has_common_children(index, local_path)? => Some(index),
+                _ => None,
+            },
+            EntryKind::File => {
+                let size = metadata.len();
+                let modified_at = DateTimeUtc::from(metadata.modified()?);
+                let unchanged = same_inode.filter(|&index| {
+                    self.entries[index]
+                        .info
+                        .content
+                        .as_ref()
+                        .is_some_and(|content| {
+                            content.original_size == size && content.modified_at == modified_at
+                        })
+                });
+                if unchanged.is_some() {
+                    unchanged
+                } else if self.file_sizes.contains(&size) {
+                    let hash = hash_file(local_path)?;
+                    let same_hash = self.by_hash.get(&hash).map_or(&[][..], |v| v.as_slice());
+                    if same_inode.is_some_and(|index| same_hash.contains(&index)) {
+                        same_inode
+                    } else {
+                        same_hash
+                            .iter()
+                            .copied()
+                            .find(|&index| !self.entries[index].matched)
+                    }
+                } else {
+                    None
+                }
+            }
+        };
+        if let Some(index) = index {
+            self.mark_matched(index);
+        }
+        Ok(index)
+    }
+}
+
+/// Finds local entries that were renamed or moved since the last sync.
+pub fn detect_renames(ctx: &Ctx, mount_points: &mut [(&MountPoint, Rules)]) -> Result<Vec<Rename>> {
+    let _status = set_status("Detecting renamed files");
+    block_in_place(|| find_renames(ctx, mount_points))
+}
+
+/// Records renames as moves on the server. Local db entries are updated accordingly,
+/// so the subsequent upload only processes actual changes.
+pub async fn record_renames(ctx: &Ctx, renames: Vec<Rename>, dry_run: bool) -> Result<()> {
+    for rename in renames {
+        // The entry could have been moved along with its renamed parent.
+        if ctx.db.get_local_entry(&rename.old_local_path)?.is_none() {
+            continue;
+        }
+        if dry_run {
+            info!(
+                "Would record move of {} to {}",
+                rename.old_local_path, rename.new_local_path
+            );
+        } else {
+            let result = ctx
+                .client
+                .request(&MovePath {
+                    old_path: encrypt_path(&rename.old_archive_path, &ctx.cipher)?,
+                    new_path: encrypt_path(&rename.new_archive_path, &ctx.cipher)?,
+                })
+                .await;
+            // In both cases it will be uploaded as a new entry instead.
+            match result {
+                Ok(stats) if stats.affected_paths == 0 => {
+                    warn!(
+                        "Failed to record move of {} to {}: no paths were moved on the server",
+                        rename.old_local_path, rename.new_local_path
+                    );
+                    continue;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!(
+                        "Failed to record move of {} to {}: {:?}",
+                        rename.old_local_path, rename.new_local_path, err
+                    );
+                    continue;
+                }
+            }
+            ctx.db
+                .move_local_entries(&rename.old_local_path, &rename.new_local_path)?;
+            info!(
+                "Recorded move of {} to {}",
+                rename.old_local_path, rename.new_local_path
+            );
+        }
+        ctx.counters.moved_entries.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+fn find_renames(ctx: &Ctx, mount_points: &mut [(&MountPoint, Rules)]) -> Result<Vec<Rename>> {
+    let mut candidates = Candidates::default();
+    for entry in ctx.db.get_all_local_entries() {
+        let (local_path, info) = entry?;
+        let Some((archive_path, rules)) = to_archive_path(&local_path, mount_points)? else {
+            continue;
+        };
+        if rules.matches_with_kind(&local_path, info.kind)? {
+            continue;
+        }
+        match fs::symlink_metadata(&local_path) {
+            Ok(_) => continue,
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        let inode = ctx.db.get_local_inode(&local_path)?;
+        candidates.add(DisappearedEntry {
+            local_path,
+            archive_path,
+            info,
+            inode,
+            matched: false,
+        })?;
+    }
+    let mut renames = Vec::new();
+    for (mount_point, rules) in mount_points {
+        if candidates.is_exhausted() {
+            break;
+        }
+        if fs::symlink_metadata(&mount_point.local_path).is_ok_and(|m| m.is_dir()) {
+            find_renames_in_dir(
+                ctx,
+                &mount_point.local_path,
+                &mount_point.archive_path,
+                rules,
+                &mut candidates,
+                &mut renames,
+            )?;
+        }
+    }
+    Ok(renames)
+}
+
+fn find_renames_in_dir(
+    ctx: &Ctx,
+    local_path: &SanitizedLocalPath,
+    archive_path: &ArchivePath,
+    rules: &mut Rules,
+    candidates: &mut Candidates,
+    renames: &mut Vec<Rename>,
+) -> Result<()> {
+    for entry in fs::read_dir(local_path)? {
+        if candidates.is_exhausted() {
+            break;
+        }
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            // Reported by upload.
+            continue;
+        };
+        let entry_local_path = local_path.join(file_name)?;
+        let metadata = fs::symlink_metadata(&entry_local_path)?;
+        // Symlinks and special files are not renamed.
+        if !(metadata.is_file() || metadata.is_dir())
+            || rules.matches_with_metadata(&entry_local_path, &metadata)?
+        {
+            continue;
+        }
+        let entry_archive_path = archive_path
+            .join_one(file_name)
+            .map_err(|err| anyhow!("failed to construct archive path: {:?}", err))?;
+        if ctx.db.get_local_entry(&entry_local_path)?.is_none() {
+            if let Some(index) = candidates.find_match(&entry_local_path, &metadata)? {
+                let old = &candidates.entries[index];
+                renames.push(Rename {
+                    old_local_path: old.local_path.clone(),
+                    old_archive_path: old.archive_path.clone(),
+                    new_local_path: entry_local_path,
+                    new_archive_path: entry_archive_path,
+                });
+                // Nested entries are moved along with the directory.
+                continue;
+            }
+        }
+        if metadata.is_dir() {
+            find_renames_in_dir(
+                ctx,
+                &entry_local_path,
+                &entry_archive_path,
+                rules,
+                candidates,
+                renames,
+            )?;
+        }
+    }
+    Ok(())
+}
@@ -1,35 +1,135 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use byte_unit::Byte;
+use fs_err::symlink_metadata;
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use rammingen_protocol::EntryKind;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    fs::Metadata,
+    io::ErrorKind,
+    time::{Duration, SystemTime},
+};
+use tracing::warn;
 
-use crate::path::SanitizedLocalPath;
+use crate::{path::SanitizedLocalPath, trash::TRASH_DIR_NAME};
+
+/// Name of the files with additional exclude rules in gitignore format.
+///
+/// Patterns in the file apply to the directory that contains it and all its nested paths.
+/// Patterns in nested directories take precedence, so they can re-include paths
+/// excluded by a parent directory's file using `!`. Paths excluded by the config
+/// can't be re-included.
+pub const IGNORE_FILE_NAME: &str = ".rammingenignore";
 
 #[derive(Debug, Clone)]
 pub struct Rules {
-    rules: Vec<Rule>,
+    /// Rule lists in the order they were passed to `new`.
+    rules: Vec<Vec<Rule>>,
+    /// Whether any of the rules needs metadata of the checked path.
+    needs_metadata: bool,
     root: SanitizedLocalPath,
     cache: HashMap<SanitizedLocalPath, bool>,
+    /// Parsed ignore files by their directory.
+    ignore_files: HashMap<SanitizedLocalPath, Option<Gitignore>>,
+}
+
+/// Explanation of why a path is excluded.
+#[derive(Debug)]
+pub struct ExcludeTrace {
+    /// Path that matched the rule. It's an ancestor of the checked path
+    /// if the checked path is excluded only because of it.
+    pub path: SanitizedLocalPath,
+    pub reason: ExcludeReason,
+}
+
+#[derive(Debug)]
+pub enum ExcludeReason {
+    /// Temporary download files and the trash are always excluded.
+    Internal,
+    /// `index`-th rule of the `list`-th rule list passed to `Rules::new`.
+    Rule {
+        list: usize,
+        index: usize,
+        rule: Rule,
+    },
+    IgnoreFile {
+        file: SanitizedLocalPath,
+        pattern: String,
+    },
 }
 
 impl Rules {
     pub fn new(rules: &[&[Rule]], root: SanitizedLocalPath) -> Self {
-        let mut vec = Vec::new();
-        for &rules in rules {
-            vec.extend_from_slice(rules);
-        }
         Self {
-            rules: vec,
+            rules: rules.iter().map(|rules| rules.to_vec()).collect(),
+            needs_metadata: rules
+                .iter()
+                .flat_map(|rules| *rules)
+                .any(Rule::needs_metadata),
             root,
             cache: HashMap::new(),
+            ignore_files: HashMap::new(),
         }
     }
 
     pub fn matches(&mut self, path: &SanitizedLocalPath) -> Result<bool> {
+        self.matches_cached(path, None, None)
+    }
+
+    /// Same as `matches`, but uses already available metadata of `path` instead of
+    /// requesting it again.
+    pub fn matches_with_metadata(
+        &mut self,
+        path: &SanitizedLocalPath,
+        metadata: &Metadata,
+    ) -> Result<bool> {
+        self.matches_cached(path, Some(metadata), None)
+    }
+
+    /// Same as `matches`, but uses the known kind of `path` to match directory-only
+    /// patterns, so that they also apply if the path was deleted or isn't downloaded yet.
+    pub fn matches_with_kind(
+        &mut self,
+        path: &SanitizedLocalPath,
+        kind: EntryKind,
+    ) -> Result<bool> {
+        self.matches_cached(path, None, Some(kind))
+    }
+
+    /// Returns the reason why `path` is excluded, or `None` if it's not excluded.
+    pub fn explain(&mut self, path: &SanitizedLocalPath) -> Result<Option<ExcludeTrace>> {
+        if path == &self.root {
+            return Ok(None);
+        }
+        if let Some(parent) = path.parent()? {
+            if self.matches_with_kind(&parent, EntryKind::Directory)? {
+                return self.explain(&parent);
+            }
+        }
+        Ok(self
+            .exclude_reason(path, None, None)?
+            .map(|reason| ExcludeTrace {
+                path: path.clone(),
+                reason,
+            }))
+    }
+
+    fn matches_cached(
+        &mut self,
+        path: &SanitizedLocalPath,
+        metadata: Option<&Metadata>,
+        kind: Option<EntryKind>,
+    ) -> Result<bool> {
         if let Some(value) = self.cache.get(path) {
             Ok(*value)
         } else {
-            let value = self.matches_inner(path);
+            let value = self.matches_inner(path, metadata, kind);
             if let Ok(value) = &value {
                 self.cache.insert(path.clone(), *value);
             }
@@ -37,31 +137,137 @@ impl Rules {
         }
     }
 
-    fn matches_inner(&mut self, path: &SanitizedLocalPath) -> Result<bool> {
+    fn matches_inner(
+        &mut self,
+        path: &SanitizedLocalPath,
+        metadata: Option<&Metadata>,
+        kind: Option<EntryKind>,
+    ) -> Result<bool> {
         if path == &self.root {
             return Ok(false);
         }
+        if let Some(parent) = path.parent()? {
+            if self.matches_with_kind(&parent, EntryKind::Directory)? {
+                return Ok(true);
+            }
+        }
+        Ok(self.exclude_reason(path, metadata, kind)?.is_some())
+    }
+
+    /// Checks `path` itself, without its ancestors.
+    ///
+    /// `kind` takes precedence over `metadata` when checking whether `path` is a directory.
+    fn exclude_reason(
+        &mut self,
+        path: &SanitizedLocalPath,
+        metadata: Option<&Metadata>,
+        kind: Option<EntryKind>,
+    ) -> Result<Option<ExcludeReason>> {
         if path
             .file_name()
             .map_or(false, |name| name.ends_with(".rammingen.part"))
+            || self.is_trash_dir(path)?
         {
-            return Ok(true);
+            return Ok(Some(ExcludeReason::Internal));
         }
-        if let Some(parent) = path.parent()? {
-            if self.matches(&parent)? {
-                return Ok(true);
+
+        // Metadata is only requested if a rule needs it.
+        let owned_metadata;
+        let (metadata, is_dir) = match metadata {
+            Some(metadata) => (Some(metadata), Some(metadata.is_dir())),
+            None if self.needs_metadata => {
+                owned_metadata = metadata_if_exists(path)?;
+                let is_dir = owned_metadata.as_ref().map_or(false, |m| m.is_dir());
+                (owned_metadata.as_ref(), Some(is_dir))
+            }
+            None => (None, None),
+        };
+        for (list, rules) in self.rules.iter().enumerate() {
+            for (index, rule) in rules.iter().enumerate() {
+                if rule.matches(path, metadata)? {
+                    return Ok(Some(ExcludeReason::Rule {
+                        list,
+                        index,
+                        rule: rule.clone(),
+                    }));
+                }
             }
         }
+        let is_dir = kind.map(|kind| kind == EntryKind::Directory).or(is_dir);
+        self.ignore_files_reason(path, is_dir)
+    }
 
-        for rule in &self.rules {
-            if rule.matches(path)? {
-                return Ok(true);
+    /// The trash directory is located in the mount point root, or next to the file
+    /// if the mount point is a single file.
+    fn is_trash_dir(&self, path: &SanitizedLocalPath) -> Result<bool> {
+        if path.file_name() != Some(TRASH_DIR_NAME) {
+            return Ok(false);
+        }
+        let Some(parent) = path.parent()? else {
+            return Ok(false);
+        };
+        Ok(parent == self.root || Some(parent) == self.root.parent()?)
+    }
+
+    /// Checks patterns from ignore files in all parent directories of `path`
+    /// within the root, starting from the closest one.
+    ///
+    /// If `is_dir` is unknown, it's requested only if there is an ignore file to check.
+    fn ignore_files_reason(
+        &mut self,
+        path: &SanitizedLocalPath,
+        mut is_dir: Option<bool>,
+    ) -> Result<Option<ExcludeReason>> {
+        let mut dir = path.parent()?;
+        while let Some(current) = dir {
+            if !current.as_path().starts_with(&self.root) {
+                break;
             }
+            if let Some(ignore_file) = self.ignore_file(&current)? {
+                if is_dir.is_none() {
+                    is_dir = Some(metadata_if_exists(path)?.map_or(false, |m| m.is_dir()));
+                }
+                match ignore_file.matched(path, is_dir == Some(true)) {
+                    Match::None => {}
+                    Match::Ignore(glob) => {
+                        return Ok(Some(ExcludeReason::IgnoreFile {
+                            file: current.join(IGNORE_FILE_NAME)?,
+                            pattern: glob.original().to_string(),
+                        }))
+                    }
+                    Match::Whitelist(_) => return Ok(None),
+                }
+            }
+            if current == self.root {
+                break;
+            }
+            dir = current.parent()?;
+        }
+        Ok(None)
+    }
+
+    fn ignore_file(&mut self, dir: &SanitizedLocalPath) -> Result<Option<&Gitignore>> {
+        if !self.ignore_files.contains_key(dir) {
+            let path = dir.join(IGNORE_FILE_NAME)?;
+            let ignore_file = if symlink_metadata(&path).map_or(false, |m| m.is_file()) {
+                let mut builder = GitignoreBuilder::new(dir);
+                if let Some(err) = builder.add(&path) {
+                    warn!("Invalid patterns in {}: {}", path, err);
+                }
+                Some(builder.build()?)
+            } else {
+                None
+            };
+            self.ignore_files.insert(dir.clone(), ignore_file);
         }
-        Ok(false)
+        Ok(self.ignore_files[dir].as_ref())
     }
 }
 
+/// An exclude rule from the config.
+///
+/// Rules based on size and modification time only apply to files.
+/// If a path doesn't exist, only rules that don't need its metadata can match it.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Rule {
@@ -73,11 +279,43 @@ pub enum Rule {
         path: SanitizedLocalPath,
         except: Vec<String>,
     },
+    /// A glob pattern. It's matched against the file name if it doesn't contain `/`,
+    /// and against the whole path otherwise.
+    Glob(GlobPattern),
+    LargerThan(Byte),
+    /// Files that were last modified earlier than the specified time ago (e.g. "30days").
+    OlderThan(#[serde(with = "humantime_serde")] Duration),
+    /// Files that were last modified later than the specified time ago (e.g. "10min").
+    NewerThan(#[serde(with = "humantime_serde")] Duration),
+    FileType(FileType),
+    /// Matches paths that match `rule` but don't match any of the `except` rules.
+    WithExcept {
+        rule: Box<Rule>,
+        except: Vec<Rule>,
+    },
 }
 
 impl Rule {
-    fn matches(&self, path: &SanitizedLocalPath) -> Result<bool> {
+    /// Whether the rule can match a path only using its metadata.
+    fn needs_metadata(&self) -> bool {
+        match self {
+            Rule::LargerThan(_) | Rule::OlderThan(_) | Rule::NewerThan(_) => true,
+            Rule::FileType(file_type) => *file_type != FileType::Hidden,
+            Rule::WithExcept { rule, except } => {
+                rule.needs_metadata() || except.iter().any(Rule::needs_metadata)
+            }
+            Rule::NameEquals(_)
+            | Rule::NameMatches(_)
+            | Rule::PathEquals(_)
+            | Rule::PathMatches(_)
+            | Rule::SubdirsOf { .. }
+            | Rule::Glob(_) => false,
+        }
+    }
+
+    fn matches(&self, path: &SanitizedLocalPath, metadata: Option<&Metadata>) -> Result<bool> {
         let name = path.file_name().unwrap_or(path.as_str());
+        let file_metadata = metadata.filter(|m| m.is_file());
         let r = match self {
             Rule::NameEquals(rule) => rule == name,
             Rule::NameMatches(rule) => rule.is_match(name),
@@ -93,11 +331,123 @@ impl Rule {
                     false
                 }
             }
+            Rule::Glob(rule) => {
+                if rule.pattern.contains('/') {
+                    rule.matcher.is_match(path.as_path())
+                } else {
+                    rule.matcher.is_match(name)
+                }
+            }
+            Rule::LargerThan(size) => file_metadata.map_or(false, |m| m.len() > size.get_bytes()),
+            Rule::OlderThan(duration) => {
+                if let Some(metadata) = file_metadata {
+                    modified_ago(metadata)? > *duration
+                } else {
+                    false
+                }
+            }
+            Rule::NewerThan(duration) => {
+                if let Some(metadata) = file_metadata {
+                    modified_ago(metadata)? < *duration
+                } else {
+                    false
+                }
+            }
+            Rule::FileType(file_type) => file_type.matches(name, metadata),
+            Rule::WithExcept { rule, except } => {
+                if rule.matches(path, metadata)? {
+                    let mut any_except = false;
+                    for rule in except {
+                        if rule.matches(path, metadata)? {
+                            any_except = true;
+                            break;
+                        }
+                    }
+                    !any_except
+                } else {
+                    false
+                }
+            }
         };
         Ok(r)
     }
 }
 
+/// Returns `None` if the path doesn't exist, e.g. if it was deleted.
+fn metadata_if_exists(path: &SanitizedLocalPath) -> Result<Option<Metadata>> {
+    match symlink_metadata(path) {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Returns time since the last modification. Modification time in the future counts as zero.
+fn modified_ago(metadata: &Metadata) -> Result<Duration> {
+    Ok(SystemTime::now()
+        .duration_since(metadata.modified()?)
+        .unwrap_or_default())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct GlobPattern {
+    pattern: String,
+    matcher: GlobMatcher,
+}
+
+impl TryFrom<String> for GlobPattern {
+    type Error = anyhow::Error;
+
+    fn try_from(pattern: String) -> Result<Self> {
+        let matcher = GlobBuilder::new(&pattern)
+            .literal_separator(true)
+            .build()
+            .map_err(|err| anyhow!("invalid glob pattern {:?}: {}", pattern, err))?
+            .compile_matcher();
+        Ok(Self { pattern, matcher })
+    }
+}
+
+impl From<GlobPattern> for String {
+    fn from(value: GlobPattern) -> Self {
+        value.pattern
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileType {
+    Socket,
+    Fifo,
+    /// Block or character device.
+    Device,
+    /// Files and directories with names starting with `.`.
+    Hidden,
+}
+
+impl FileType {
+    fn matches(self, name: &str, metadata: Option<&Metadata>) -> bool {
+        if self == FileType::Hidden {
+            return name.starts_with('.');
+        }
+        #[cfg(target_family = "unix")]
+        if let Some(metadata) = metadata {
+            use std::os::unix::fs::FileTypeExt;
+
+            let file_type = metadata.file_type();
+            return match self {
+                FileType::Socket => file_type.is_socket(),
+                FileType::Fifo => file_type.is_fifo(),
+                FileType::Device => file_type.is_block_device() || file_type.is_char_device(),
+                FileType::Hidden => unreachable!(),
+            };
+        }
+        let _ = metadata;
+        false
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -146,6 +496,16 @@ mod tests {
         i(&mut rules, "/tmp/1/abd/other");
     }
 
+    #[test]
+    fn internal() {
+        let mut rules = rules(r#"[]"#);
+        e(&mut rules, "/tmp/1/.rammingen.trash");
+        e(&mut rules, "/tmp/1/.rammingen.trash/abc");
+        e(&mut rules, "/tmp/1/abc.rammingen.part");
+        e(&mut rules, "/tmp/1/dir/abc.rammingen.part");
+        i(&mut rules, "/tmp/1/dir/.rammingen.trash");
+    }
+
     #[test]
     fn with_final() {
         let mut rules = rules(
@@ -160,6 +520,158 @@ mod tests {
         e(&mut rules, "/tmp/1/target/2/a");
     }
 
+    #[test]
+    fn with_ignore_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = SanitizedLocalPath::new(dir.path()).unwrap();
+        let path = |s: &str| root.join(s).unwrap();
+        fs_err::create_dir_all(path("build/out")).unwrap();
+        fs_err::create_dir_all(path("src/build")).unwrap();
+        fs_err::create_dir_all(path("logs")).unwrap();
+        fs_err::write(path(IGNORE_FILE_NAME), "/build\n*.log\n").unwrap();
+        fs_err::write(path("logs").join(IGNORE_FILE_NAME).unwrap(), "!keep.log\n").unwrap();
+
+        let mut rules = Rules::new(&[], root.clone());
+        assert!(!rules.matches(&path(IGNORE_FILE_NAME)).unwrap());
+        assert!(rules.matches(&path("build")).unwrap());
+        assert!(rules.matches(&path("build/out")).unwrap());
+        assert!(!rules.matches(&path("src/build")).unwrap());
+        assert!(rules.matches(&path("a.log")).unwrap());
+        assert!(rules.matches(&path("src/a.log")).unwrap());
+        assert!(rules.matches(&path("logs/a.log")).unwrap());
+        assert!(!rules.matches(&path("logs/keep.log")).unwrap());
+    }
+
+    #[test]
+    fn with_known_kind() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = SanitizedLocalPath::new(dir.path()).unwrap();
+        let path = |s: &str| root.join(s).unwrap();
+        fs_err::write(path(IGNORE_FILE_NAME), "out/\n").unwrap();
+
+        // Paths don't exist, so only the known kind tells that they are directories.
+        let mut rules = Rules::new(&[], root.clone());
+        assert!(!rules.matches(&path("out")).unwrap());
+        let mut rules = Rules::new(&[], root.clone());
+        assert!(rules
+            .matches_with_kind(&path("out"), EntryKind::Directory)
+            .unwrap());
+        assert!(rules.matches(&path("out/file")).unwrap());
+        assert!(!rules
+            .matches_with_kind(&path("a/out"), EntryKind::File)
+            .unwrap());
+    }
+
+    #[test]
+    fn explain() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = SanitizedLocalPath::new(dir.path()).unwrap();
+        let path = |s: &str| root.join(s).unwrap();
+        fs_err::create_dir_all(path("logs")).unwrap();
+        fs_err::write(path("logs").join(IGNORE_FILE_NAME).unwrap(), "*.log\n").unwrap();
+
+        let parse = |s: &str| json5::from_str::<Vec<Rule>>(s).unwrap();
+        let mut rules = Rules::new(
+            &[
+                &parse(r#"[{ name_equals: "a" }]"#),
+                &parse(r#"[{ name_equals: "b" }, { glob: "*.tmp" }]"#),
+            ],
+            root.clone(),
+        );
+
+        assert!(rules.explain(&path("c")).unwrap().is_none());
+        assert!(rules.explain(&path("logs/a.txt")).unwrap().is_none());
+
+        let trace = rules.explain(&path("c.tmp")).unwrap().unwrap();
+        assert_eq!(trace.path, path("c.tmp"));
+        assert!(matches!(
+            trace.reason,
+            ExcludeReason::Rule {
+                list: 1,
+                index: 1,
+                ..
+            }
+        ));
+
+        let trace = rules.explain(&path("a")).unwrap().unwrap();
+        assert!(matches!(
+            trace.reason,
+            ExcludeReason::Rule {
+                list: 0,
+                index: 0,
+                ..
+            }
+        ));
+
+        let trace = rules.explain(&path("b/c/d")).unwrap().unwrap();
+        assert_eq!(trace.path, path("b"));
+        assert!(matches!(
+            trace.reason,
+            ExcludeReason::Rule {
+                list: 1,
+                index: 0,
+                ..
+            }
+        ));
+
+        let trace = rules.explain(&path("logs/a.log")).unwrap().unwrap();
+        assert_eq!(trace.path, path("logs/a.log"));
+        match trace.reason {
+            ExcludeReason::IgnoreFile { file, pattern } => {
+                assert_eq!(file, path("logs").join(IGNORE_FILE_NAME).unwrap());
+                assert_eq!(pattern, "*.log");
+            }
+            reason => panic!("unexpected reason: {reason:?}"),
+        }
+
+        let trace = rules
+            .explain(&path(".rammingen.trash/1/file"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(trace.path, path(".rammingen.trash"));
+        assert!(matches!(trace.reason, ExcludeReason::Internal));
+    }
+
+    #[test]
+    fn with_glob_and_except() {
+        let mut rules = rules(
+            r#"[
+            { with_except: { rule: { glob: "*.log" }, except: [{ name_equals: "keep.log" }] } },
+            { glob: "/tmp/1/*/cache" },
+        ]"#,
+        );
+        e(&mut rules, "/tmp/1/a.log");
+        e(&mut rules, "/tmp/1/dir/a.log");
+        i(&mut rules, "/tmp/1/keep.log");
+        i(&mut rules, "/tmp/1/a.log2");
+        e(&mut rules, "/tmp/1/dir/cache");
+        i(&mut rules, "/tmp/1/dir/dir2/cache");
+    }
+
+    #[test]
+    fn with_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = SanitizedLocalPath::new(dir.path()).unwrap();
+        let path = |s: &str| root.join(s).unwrap();
+        fs_err::create_dir(path("big_dir")).unwrap();
+        fs_err::write(path("small"), [0; 10]).unwrap();
+        fs_err::write(path("big"), [0; 2000]).unwrap();
+
+        let parse = |s: &str| json5::from_str::<Vec<Rule>>(s).unwrap();
+        let mut rules = Rules::new(&[&parse(r#"[{ larger_than: "1 KB" }]"#)], root.clone());
+        assert!(!rules.matches(&path("small")).unwrap());
+        assert!(rules.matches(&path("big")).unwrap());
+        assert!(!rules.matches(&path("big_dir")).unwrap());
+        assert!(!rules.matches(&path("deleted")).unwrap());
+
+        let mut rules = Rules::new(&[&parse(r#"[{ newer_than: "1h" }]"#)], root.clone());
+        assert!(rules.matches(&path("small")).unwrap());
+        assert!(!rules.matches(&path("big_dir")).unwrap());
+
+        let mut rules = Rules::new(&[&parse(r#"[{ older_than: "1h" }]"#)], root.clone());
+        assert!(!rules.matches(&path("small")).unwrap());
+    }
+
     #[test]
     fn with_subdirs() {
         let mut rules = rules(
@@ -2,28 +2,41 @@ use std::{collections::HashSet, sync::Arc};
 
 use crate::{
     download::download_latest,
+    mass_change::{check_mass_changes, is_unexpectedly_empty},
     pull_updates::pull_updates,
+    rename::{detect_renames, record_renames},
     rules::Rules,
+    trash::clean_trash,
     upload::{find_local_deletions, upload},
     Ctx,
 };
 use anyhow::Result;
-use itertools::Itertools;
+use tracing::warn;
 
-pub async fn sync(ctx: &Arc<Ctx>, dry_run: bool) -> Result<()> {
+pub async fn sync(ctx: &Arc<Ctx>, dry_run: bool, force: bool) -> Result<()> {
     let mut existing_paths = HashSet::new();
-    let mut mount_points = ctx
-        .config
-        .mount_points
-        .iter()
-        .map(|mount_point| {
-            let rules = Rules::new(
-                &[&ctx.config.always_exclude, &mount_point.exclude],
-                mount_point.local_path.clone(),
+    let mut mount_points = Vec::new();
+    for mount_point in &ctx.config.mount_points {
+        if is_unexpectedly_empty(ctx, mount_point)? {
+            warn!(
+                "Skipping mount point {} because it's missing or empty. \
+                Use `remove` command if you want to delete its content from the archive.",
+                mount_point.local_path
             );
-            (mount_point, rules)
-        })
-        .collect_vec();
+            continue;
+        }
+        let rules = Rules::new(
+            &[&ctx.config.always_exclude, &mount_point.exclude],
+            mount_point.local_path.clone(),
+        );
+        mount_points.push((mount_point, rules));
+    }
+    let renames = detect_renames(ctx, &mut mount_points)?;
+    if !force {
+        check_mass_changes(ctx, &mut mount_points, &renames, dry_run)?;
+    }
+    // Moves are recorded first, so that renamed entries are not uploaded again.
+    record_renames(ctx, renames, dry_run).await?;
 
     for (mount_point, rules) in &mut mount_points {
         upload(
@@ -39,7 +52,7 @@ pub async fn sync(ctx: &Arc<Ctx>, dry_run: bool) -> Result<()> {
     }
     find_local_deletions(ctx, &mut mount_points, &existing_paths, dry_run).await?;
     pull_updates(ctx).await?;
-    for mount_point in &ctx.config.mount_points {
+    for (mount_point, _) in &mount_points {
         download_latest(
             ctx,
             &mount_point.archive_path,
@@ -52,6 +65,14 @@ pub async fn sync(ctx: &Arc<Ctx>, dry_run: bool) -> Result<()> {
             dry_run,
         )
         .await?;
+        if !dry_run {
+            if let Err(err) = clean_trash(ctx.config.trash_retention, mount_point) {
+                warn!(
+                    "Failed to clean trash of {}: {:?}",
+                    mount_point.local_path, err
+                );
+            }
+        }
     }
     Ok(())
 }
@@ -1,11 +1,7 @@
 use std::fmt::Write as _;
 use std::process;
 use std::time::Duration;
-use std::{
-    fmt::Display,
-    io::{Stdout, Write},
-    sync::Arc,
-};
+use std::{fmt::Display, io::Write, sync::Arc};
 
 use crossterm::{
     cursor,
@@ -23,7 +19,8 @@ use tracing::{field::Visit, Subscriber};
 use tracing_subscriber::Layer;
 
 struct Term {
-    stdout: Stdout,
+    /// Stdout, unless `use_stderr` was called.
+    stdout: Box<dyn Write + Send>,
     current_status: Option<String>,
 }
 
@@ -56,6 +53,12 @@ pub fn clear_status() {
     term().clear_status()
 }
 
+/// Redirects log messages and status to stderr, so that stdout only contains
+/// the command output.
+pub fn use_stderr() {
+    term().stdout = Box::new(std::io::stderr());
+}
+
 pub struct StatusUpdaterGuard(Option<oneshot::Sender<()>>);
 
 impl Drop for StatusUpdaterGuard {
@@ -102,7 +105,7 @@ impl Term {
             }
         });
         Self {
-            stdout: std::io::stdout(),
+            stdout: Box::new(std::io::stdout()),
             current_status: None,
         }
     }
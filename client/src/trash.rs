@@ -0,0 +1,282 @@
+//! Local trash for files deleted or replaced by sync.
+//!
+//! Each mount point has its own trash directory, so that files can be moved there
+//! without copying. Every trashed file is stored as `<trash>/<id>/<path relative to mount point>`,
+//! where `id` is the time when the file was trashed.
+
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use fs_err::{create_dir_all, read_dir, remove_dir_all, remove_file, rename, symlink_metadata};
+use prettytable::{format::FormatBuilder, row, Table};
+use rammingen_protocol::{util::try_exists, DateTimeUtc};
+use tracing::{info, warn};
+
+use crate::{config::MountPoint, info::pretty_time, path::SanitizedLocalPath, Ctx};
+
+/// Name of the trash directory in the mount point root.
+/// It's always excluded from sync.
+pub const TRASH_DIR_NAME: &str = ".rammingen.trash";
+
+const TRASH_ID_FORMAT: &str = "%Y-%m-%d_%H-%M-%S%.9f";
+
+/// Returns the directory that contains the trash directory of the mount point.
+fn trash_base_dir(root_local_path: &SanitizedLocalPath) -> Result<SanitizedLocalPath> {
+    if symlink_metadata(root_local_path).map_or(false, |m| m.is_dir()) {
+        Ok(root_local_path.clone())
+    } else {
+        root_local_path
+            .parent()?
+            .ok_or_else(|| anyhow!("failed to get parent of {}", root_local_path))
+    }
+}
+
+/// Moves a file to the trash of the mount point `root_local_path`.
+/// Removes it instead if the trash is disabled (`retention` is zero).
+pub fn trash_file(
+    retention: Duration,
+    root_local_path: &SanitizedLocalPath,
+    local_path: &SanitizedLocalPath,
+) -> Result<()> {
+    if retention.is_zero() {
+        remove_file(local_path)?;
+        return Ok(());
+    }
+    let base_dir = trash_base_dir(root_local_path)?;
+    let relative_path = local_path.as_path().strip_prefix(&base_dir)?;
+    let trash_dir = base_dir.join(TRASH_DIR_NAME)?;
+    let mut item_dir;
+    loop {
+        item_dir = trash_dir.join(Utc::now().format(TRASH_ID_FORMAT).to_string())?;
+        if !try_exists(&item_dir)? {
+            break;
+        }
+    }
+    let trash_path = item_dir.join(relative_path)?;
+    let trash_parent = trash_path
+        .parent()?
+        .ok_or_else(|| anyhow!("failed to get parent of {}", trash_path))?;
+    create_dir_all(&trash_parent)?;
+    rename(local_path, &trash_path)?;
+    Ok(())
+}
+
+struct TrashItem {
+    id: String,
+    trashed_at: DateTimeUtc,
+    /// Path of the file in the trash.
+    trash_path: PathBuf,
+    /// Path where the file was before it was trashed.
+    original_path: SanitizedLocalPath,
+}
+
+fn trash_items(mount_point: &MountPoint) -> Result<Vec<TrashItem>> {
+    let base_dir = trash_base_dir(&mount_point.local_path)?;
+    let trash_dir = base_dir.join(TRASH_DIR_NAME)?;
+    if !try_exists(&trash_dir)? {
+        return Ok(Vec::new());
+    }
+    let mut items = Vec::new();
+    for entry in read_dir(&trash_dir)? {
+        let item_dir = entry?.path();
+        let id = item_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("invalid trash item name: {:?}", item_dir))?
+            .to_string();
+        let trashed_at = match NaiveDateTime::parse_from_str(&id, TRASH_ID_FORMAT) {
+            Ok(time) => Utc.from_utc_datetime(&time),
+            Err(err) => {
+                warn!("Unexpected file in trash: {:?}: {}", item_dir, err);
+                continue;
+            }
+        };
+        let trash_path = find_trashed_file(&item_dir)?;
+        let original_path = base_dir.join(trash_path.strip_prefix(&item_dir)?)?;
+        items.push(TrashItem {
+            id,
+            trashed_at,
+            trash_path,
+            original_path,
+        });
+    }
+    items.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(items)
+}
+
+/// Each trash item directory contains only parent directories of the trashed file.
+fn find_trashed_file(item_dir: &Path) -> Result<PathBuf> {
+    let mut path = item_dir.to_path_buf();
+    while symlink_metadata(&path)?.is_dir() {
+        let mut entries = read_dir(&path)?.collect::<Result<Vec<_>, _>>()?;
+        if entries.len() != 1 {
+            bail!("unexpected content of trash directory {:?}", item_dir);
+        }
+        path = entries.remove(0).path();
+    }
+    Ok(path)
+}
+
+/// Removes files that were trashed earlier than `retention` ago.
+pub fn clean_trash(retention: Duration, mount_point: &MountPoint) -> Result<()> {
+    let Ok(retention) = chrono::Duration::from_std(retention) else {
+        return Ok(());
+    };
+    let base_dir = trash_base_dir(&mount_point.local_path)?;
+    let trash_dir = base_dir.join(TRASH_DIR_NAME)?;
+    for item in trash_items(mount_point)? {
+        if Utc::now() - item.trashed_at > retention {
+            remove_dir_all(trash_dir.join(&item.id)?)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn list_trash(ctx: &Ctx) -> Result<()> {
+    let mut table = Table::new();
+    table.set_format(FormatBuilder::new().column_separator(' ').build());
+    table.add_row(row!["Id", "Trashed", "Path"]);
+    for mount_point in &ctx.config.mount_points {
+        for item in trash_items(mount_point)? {
+            table.add_row(row![
+                item.id,
+                pretty_time(item.trashed_at),
+                item.original_path
+            ]);
+        }
+    }
+    info!("{table}");
+    Ok(())
+}
+
+/// Moves a trashed file back to its original path.
+///
+/// The file is not added to the local db, so the next sync treats it as a new local file
+/// and uploads it, replacing the archive version of the path if there is one.
+pub fn restore_from_trash(mount_points: &[MountPoint], id: &str) -> Result<()> {
+    for mount_point in mount_points {
+        let Some(item) = trash_items(mount_point)?
+            .into_iter()
+            .find(|item| item.id == id)
+        else {
+            continue;
+        };
+        if try_exists(&item.original_path)? {
+            bail!("{} already exists", item.original_path);
+        }
+        let parent = item
+            .original_path
+            .parent()?
+            .ok_or_else(|| anyhow!("failed to get parent of {}", item.original_path))?;
+        create_dir_all(&parent)?;
+        rename(&item.trash_path, &item.original_path)?;
+        let base_dir = trash_base_dir(&mount_point.local_path)?;
+        remove_dir_all(base_dir.join(TRASH_DIR_NAME)?.join(&item.id)?)?;
+        info!("Restored {}", item.original_path);
+        warn!("It will be uploaded to the archive as a new version on the next sync");
+        return Ok(());
+    }
+    bail!("trash item not found: {}", id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::sleep;
+
+    use fs_err::{read_to_string, write};
+
+    use super::*;
+
+    const RETENTION: Duration = Duration::from_secs(3600);
+
+    fn mount_point(local_path: &SanitizedLocalPath) -> MountPoint {
+        MountPoint {
+            local_path: local_path.clone(),
+            archive_path: "ar:/".parse().unwrap(),
+            exclude: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn trash_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = SanitizedLocalPath::new(dir.path()).unwrap();
+        let mount_point = mount_point(&root);
+        let file = root.join("dir/file").unwrap();
+        create_dir_all(root.join("dir").unwrap()).unwrap();
+        write(&file, "content").unwrap();
+
+        trash_file(RETENTION, &root, &file).unwrap();
+        assert!(!try_exists(&file).unwrap());
+        let items = trash_items(&mount_point).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].original_path, file);
+        assert_eq!(read_to_string(&items[0].trash_path).unwrap(), "content");
+
+        write(&file, "new content").unwrap();
+        assert!(restore_from_trash(&[mount_point.clone()], &items[0].id).is_err());
+        assert_eq!(trash_items(&mount_point).unwrap().len(), 1);
+
+        remove_file(&file).unwrap();
+        restore_from_trash(&[mount_point.clone()], &items[0].id).unwrap();
+        assert_eq!(read_to_string(&file).unwrap(), "content");
+        assert!(trash_items(&mount_point).unwrap().is_empty());
+        assert!(restore_from_trash(&[mount_point], &items[0].id).is_err());
+    }
+
+    #[test]
+    fn single_file_mount_point() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = SanitizedLocalPath::new(dir.path())
+            .unwrap()
+            .join("file")
+            .unwrap();
+        let mount_point = mount_point(&file);
+        write(&file, "content").unwrap();
+
+        trash_file(RETENTION, &file, &file).unwrap();
+        assert!(try_exists(dir.path().join(TRASH_DIR_NAME)).unwrap());
+        let items = trash_items(&mount_point).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].original_path, file);
+
+        restore_from_trash(&[mount_point], &items[0].id).unwrap();
+        assert_eq!(read_to_string(&file).unwrap(), "content");
+    }
+
+    #[test]
+    fn disabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = SanitizedLocalPath::new(dir.path()).unwrap();
+        let file = root.join("file").unwrap();
+        write(&file, "content").unwrap();
+
+        trash_file(Duration::ZERO, &root, &file).unwrap();
+        assert!(!try_exists(&file).unwrap());
+        assert!(!try_exists(root.join(TRASH_DIR_NAME).unwrap()).unwrap());
+    }
+
+    #[test]
+    fn clean() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = SanitizedLocalPath::new(dir.path()).unwrap();
+        let mount_point = mount_point(&root);
+        for name in ["file1", "file2"] {
+            let file = root.join(name).unwrap();
+            write(&file, "content").unwrap();
+            trash_file(RETENTION, &root, &file).unwrap();
+        }
+        assert_eq!(trash_items(&mount_point).unwrap().len(), 2);
+
+        clean_trash(RETENTION, &mount_point).unwrap();
+        assert_eq!(trash_items(&mount_point).unwrap().len(), 2);
+
+        sleep(Duration::from_millis(10));
+        clean_trash(Duration::from_millis(1), &mount_point).unwrap();
+        assert!(trash_items(&mount_point).unwrap().is_empty());
+    }
+}
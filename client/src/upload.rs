@@ -1,4 +1,5 @@
 use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
 use fs::symlink_metadata;
 use fs_err as fs;
 use futures::future::BoxFuture;
@@ -9,7 +10,7 @@ use rammingen_protocol::{
 };
 use std::{
     collections::HashSet,
-    mem,
+    io, mem,
     sync::{atomic::Ordering, Arc},
     time::Duration,
 };
@@ -25,6 +26,7 @@ use crate::{
     data::{DecryptedFileContent, LocalEntryInfo},
     encryption::{self, encrypt_content_hash, encrypt_path, encrypt_size, EncryptedFileData},
     info::pretty_size,
+    inode,
     path::SanitizedLocalPath,
     rules::Rules,
     term::{set_status, set_status_updater},
@@ -34,6 +36,65 @@ use crate::{
 const TOO_RECENT_INTERVAL: Duration = Duration::from_millis(100);
 const BATCH_SIZE: usize = 128;
 
+/// Encrypts data from stdin and uploads it as a new version of the file at `archive_path`.
+pub async fn upload_stream(
+    ctx: &Ctx,
+    archive_path: &ArchivePath,
+    unix_mode: Option<u32>,
+) -> Result<()> {
+    let file_data = {
+        let _status = set_status("Reading stdin");
+        // The input size is unknown, so multithreaded compression is always used.
+        let num_compression_workers = rayon::current_num_threads().try_into()?;
+        block_in_place(|| {
+            encryption::encrypt_reader(io::stdin().lock(), &ctx.cipher, num_compression_workers)
+        })?
+    };
+    let encrypted_hash = encrypt_content_hash(&file_data.hash, &ctx.cipher)?;
+    let exists = ctx
+        .client
+        .request(&ContentHashExists(encrypted_hash.clone()))
+        .await?;
+    if !exists {
+        let _status = set_status(format!(
+            "Uploading {}",
+            pretty_size(file_data.encrypted_size)
+        ));
+        ctx.client.upload(&encrypted_hash, file_data.file).await?;
+        ctx.counters
+            .uploaded_bytes
+            .fetch_add(file_data.encrypted_size, Ordering::SeqCst);
+    }
+    let results = ctx
+        .client
+        .request(&AddVersions(vec![AddVersion {
+            path: encrypt_path(archive_path, &ctx.cipher)?,
+            record_trigger: RecordTrigger::Upload,
+            kind: Some(EntryKind::File),
+            content: Some(FileContent {
+                modified_at: Utc::now(),
+                original_size: encrypt_size(file_data.original_size, &ctx.cipher)?,
+                encrypted_size: file_data.encrypted_size,
+                hash: encrypted_hash,
+                unix_mode,
+            }),
+        }]))
+        .await?;
+    let [result] = results.as_slice() else {
+        bail!("invalid item count in AddVersions response");
+    };
+    if result.added {
+        info!(
+            "Uploaded {} ({})",
+            archive_path,
+            pretty_size(file_data.original_size)
+        );
+    } else {
+        info!("{} is unchanged", archive_path);
+    }
+    Ok(())
+}
+
 pub fn to_archive_path<'a>(
     local_path: &SanitizedLocalPath,
     mount_points: &'a mut [(&MountPoint, Rules)],
@@ -63,7 +124,7 @@ pub async fn find_local_deletions<'a>(
     let mut local_paths = Vec::new();
 
     for entry in ctx.db.get_all_local_entries().rev() {
-        let (local_path, _data) = entry?;
+        let (local_path, data) = entry?;
         if existing_paths.contains(&local_path) {
             continue;
         }
@@ -73,7 +134,7 @@ pub async fn find_local_deletions<'a>(
             else {
                 continue;
             };
-        if rules.matches(&local_path)? {
+        if rules.matches_with_kind(&local_path, data.kind)? {
             continue;
         }
         if dry_run {
@@ -200,10 +261,17 @@ fn upload_inner<'a>(
             warn!("skipping symlink: {}", local_path);
             return Ok(());
         }
-        if ctx.rules.matches(local_path)? {
+        if ctx.rules.matches_with_metadata(local_path, &metadata)? {
             debug!("ignored: {}", local_path);
             return Ok(());
         }
+        if ctx.is_mount && !ctx.dry_run {
+            if let Some(inode) = inode(&metadata) {
+                if ctx.ctx.db.get_local_inode(local_path)? != Some(inode) {
+                    ctx.ctx.db.set_local_inode(local_path, inode)?;
+                }
+            }
+        }
         let is_dir = metadata.is_dir();
         let kind = if is_dir {
             EntryKind::Directory
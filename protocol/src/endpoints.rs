@@ -1,8 +1,11 @@
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 
 use crate::{
     path::EncryptedArchivePath, DateTimeUtc, EncryptedContentHash, Entry, EntryKind,
-    EntryUpdateNumber, EntryVersion, FileContent, RecordTrigger, SourceId,
+    EntryUpdateNumber, EntryVersion, EntryVersionData, FileContent, Operation, OperationId,
+    RecordTrigger, SourceId,
 };
 
 pub trait RequestToResponse {
@@ -43,6 +46,17 @@ pub struct GetNewEntries {
 }
 streaming_response_type!(GetNewEntries, Entry);
 
+/// Waits until there are entries added or updated since the specified update number,
+/// and returns them in the same way as `GetNewEntries`.
+/// Returns no entries if there were no updates within `timeout`.
+/// The server may use a shorter timeout than requested.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WaitForNewEntries {
+    pub last_update_number: EntryUpdateNumber,
+    pub timeout: Duration,
+}
+streaming_response_type!(WaitForNewEntries, Entry);
+
 /// Returns all entries that are direct children of the specified path.
 /// Results are ordered by path.
 #[derive(Debug, Serialize, Deserialize)]
@@ -98,10 +112,13 @@ pub struct AddVersionResponse {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BulkActionStats {
     pub affected_paths: u64,
+    /// Id of the recorded operation. It can be used to undo the operation.
+    pub operation_id: OperationId,
 }
 
 /// Set the specified version as the latest one.
 /// If a directory, resets all nested paths.
+/// Fails if a retention lock forbids removal of the path.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ResetVersion {
     pub path: EncryptedArchivePath,
@@ -119,14 +136,84 @@ pub struct MovePath {
 }
 response_type!(MovePath, BulkActionStats);
 
+/// Creates a copy of `old_path` at `new_path`. The copy shares content with the original.
+/// `new_path` must not exist. If `old_path` is a directory, also copies all children.
+/// If `recorded_at` is specified, copies the version that was current at that time,
+/// otherwise the latest version is copied.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CopyPath {
+    pub old_path: EncryptedArchivePath,
+    pub new_path: EncryptedArchivePath,
+    pub recorded_at: Option<DateTimeUtc>,
+}
+response_type!(CopyPath, BulkActionStats);
+
 /// Records deletion of the specified path.
 /// If it's a directory, also records deletion of all children.
+/// Fails if a retention lock forbids removal of the path.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RemovePath {
     pub path: EncryptedArchivePath,
 }
 response_type!(RemovePath, BulkActionStats);
 
+/// Change of an archive path made by a bulk action.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PathChange {
+    /// `None` if the path didn't have any versions before.
+    pub old: Option<EntryVersionData>,
+    pub new: EntryVersionData,
+}
+
+/// Returns changes that would be made by `ResetVersion` without applying them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreviewResetVersion(pub ResetVersion);
+response_type!(PreviewResetVersion, Vec<PathChange>);
+
+/// Returns changes that would be made by `MovePath` without applying them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreviewMovePath(pub MovePath);
+response_type!(PreviewMovePath, Vec<PathChange>);
+
+/// Returns changes that would be made by `RemovePath` without applying them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreviewRemovePath(pub RemovePath);
+response_type!(PreviewRemovePath, Vec<PathChange>);
+
+/// Permanently deletes the specified path, all its children and all their versions,
+/// including versions kept in snapshots. Content that is no longer referenced by any version
+/// is removed from the storage. This can't be undone.
+/// Fails if any of the versions is protected by a retention lock.
+/// Purged entries are kept as deleted with the `Purge` record trigger.
+/// Clients forget their local data about these paths, so their local files are kept
+/// and uploaded again on the next sync.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PurgePath {
+    pub path: EncryptedArchivePath,
+    /// Must be true, otherwise the request is rejected.
+    pub confirm: bool,
+}
+response_type!(PurgePath, PurgeStats);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PurgeStats {
+    pub removed_entries: u64,
+    pub removed_versions: u64,
+    pub removed_content_files: u64,
+}
+
+/// Restores all entries changed by the specified operation to their state
+/// just before the operation. Fails if any of these entries was changed after the operation,
+/// unless `force` is true. Only operations that return `BulkActionStats` can be undone.
+/// Versions older than the detailed history retention period are merged into snapshots,
+/// so changes made by such operations can no longer be undone.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UndoOperation {
+    pub id: OperationId,
+    pub force: bool,
+}
+response_type!(UndoOperation, BulkActionStats);
+
 /// Checks whether the specified content hash is stored on the server.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ContentHashExists(pub EncryptedContentHash);
@@ -147,6 +234,14 @@ pub struct ServerStatus {
 pub struct CheckIntegrity;
 response_type!(CheckIntegrity, ());
 
+/// Returns operations that changed entries, starting from the specified time
+/// (or from the beginning if not specified). Results are ordered by time.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetOperations {
+    pub since: Option<DateTimeUtc>,
+}
+streaming_response_type!(GetOperations, Operation);
+
 /// Returns id and name of all sources.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GetSources;
@@ -54,7 +54,22 @@ impl EntryId {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Into)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, From, Into)]
+pub struct OperationId(i64);
+
+impl OperationId {
+    pub fn to_db(self) -> i64 {
+        self.0
+    }
+}
+
+impl fmt::Display for OperationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Into)]
 pub struct ContentHash(Vec<u8>);
 
 impl ContentHash {
@@ -126,6 +141,8 @@ pub enum RecordTrigger {
     Reset,
     Move,
     Remove,
+    Copy,
+    Purge,
 }
 
 impl TryFrom<i32> for RecordTrigger {
@@ -138,6 +155,8 @@ impl TryFrom<i32> for RecordTrigger {
             2 => Ok(Self::Reset),
             3 => Ok(Self::Move),
             4 => Ok(Self::Remove),
+            5 => Ok(Self::Copy),
+            6 => Ok(Self::Purge),
             _ => bail!("invalid value for RecordTrigger: {}", value),
         }
     }
@@ -215,6 +234,63 @@ pub struct EntryVersion {
     pub data: EntryVersionData,
 }
 
+/// A recorded request that changed entries.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Operation {
+    pub id: OperationId,
+    pub source_id: SourceId,
+    pub recorded_at: DateTimeUtc,
+    pub args: OperationArgs,
+    pub affected_paths: u64,
+}
+
+/// Arguments of a recorded request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OperationArgs {
+    /// Only includes paths of the versions that were actually added.
+    AddVersions {
+        paths: Vec<EncryptedArchivePath>,
+    },
+    MovePath {
+        old_path: EncryptedArchivePath,
+        new_path: EncryptedArchivePath,
+    },
+    RemovePath {
+        path: EncryptedArchivePath,
+    },
+    ResetVersion {
+        path: EncryptedArchivePath,
+        recorded_at: DateTimeUtc,
+    },
+    UndoOperation {
+        id: OperationId,
+        force: bool,
+    },
+    CopyPath {
+        old_path: EncryptedArchivePath,
+        new_path: EncryptedArchivePath,
+        recorded_at: Option<DateTimeUtc>,
+    },
+    PurgePath {
+        path: EncryptedArchivePath,
+    },
+}
+
+impl OperationArgs {
+    /// Name of the endpoint that performed the operation.
+    pub fn endpoint(&self) -> &'static str {
+        match self {
+            OperationArgs::AddVersions { .. } => "AddVersions",
+            OperationArgs::MovePath { .. } => "MovePath",
+            OperationArgs::RemovePath { .. } => "RemovePath",
+            OperationArgs::ResetVersion { .. } => "ResetVersion",
+            OperationArgs::UndoOperation { .. } => "UndoOperation",
+            OperationArgs::CopyPath { .. } => "CopyPath",
+            OperationArgs::PurgePath { .. } => "PurgePath",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileContent {
     pub modified_at: DateTimeUtc,
@@ -203,6 +203,17 @@ impl EncryptedArchivePath {
     }
 }
 
+impl FromStr for EncryptedArchivePath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let path = s
+            .strip_prefix("enar:")
+            .ok_or_else(|| anyhow!("encrypted archive path must start with 'enar:'"))?;
+        Self::from_encrypted_without_prefix(path)
+    }
+}
+
 impl fmt::Display for EncryptedArchivePath {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "enar:{}", self.0 .0)
@@ -0,0 +1,10 @@
+CREATE TABLE operations (
+    id BIGSERIAL PRIMARY KEY,
+    source_id INT NOT NULL REFERENCES sources(id) ON DELETE RESTRICT,
+    recorded_at TIMESTAMP WITH TIME ZONE NOT NULL,
+    endpoint VARCHAR NOT NULL,
+    -- `OperationArgs` serialized with bincode. It only contains encrypted paths.
+    args bytea NOT NULL,
+    affected_paths BIGINT NOT NULL
+);
+CREATE INDEX idx_operations_recorded_at ON operations (recorded_at);
@@ -0,0 +1,23 @@
+ALTER TABLE entry_versions ADD COLUMN operation_id BIGINT REFERENCES operations(id) ON DELETE SET NULL;
+CREATE INDEX idx_entry_versions_operation_id ON entry_versions (operation_id);
+
+-- Versions are linked to the operation that is currently performed in the transaction
+-- (see `start_operation`).
+CREATE OR REPLACE FUNCTION on_entry_update()
+   RETURNS TRIGGER
+   LANGUAGE plpgsql
+AS $$
+BEGIN
+    INSERT INTO entry_versions (
+        entry_id, update_number, snapshot_id, path, recorded_at, source_id,
+        record_trigger, kind, original_size, encrypted_size, modified_at, content_hash, unix_mode,
+        operation_id
+    ) VALUES (
+        NEW.id, NEW.update_number, NULL, NEW.path, NEW.recorded_at, NEW.source_id,
+        NEW.record_trigger, NEW.kind, NEW.original_size, NEW.encrypted_size,
+        NEW.modified_at, NEW.content_hash, NEW.unix_mode,
+        NULLIF(current_setting('rammingen.operation_id', true), '')::BIGINT
+    );
+    RETURN NULL;
+END;
+$$;
@@ -0,0 +1,8 @@
+CREATE TABLE retention_locks (
+    id SERIAL PRIMARY KEY,
+    path VARCHAR NOT NULL UNIQUE,
+    -- LIKE pattern that matches all nested paths.
+    nested_paths_pattern VARCHAR NOT NULL,
+    retain_for_days INT NOT NULL,
+    forbid_remove BOOLEAN NOT NULL
+);
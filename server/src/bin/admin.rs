@@ -1,9 +1,13 @@
 use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
+use rammingen_protocol::EncryptedArchivePath;
 use rammingen_server::{
     config_path,
-    util::{add_source, generate_access_token, set_access_token, sources},
+    util::{
+        add_retention_lock, add_source, generate_access_token, retention_locks, set_access_token,
+        sources,
+    },
     Config,
 };
 use sqlx::PgPool;
@@ -37,6 +41,23 @@ pub enum Command {
     UpdateAccessToken { name: String },
     /// Intializes or updates database structure.
     Migrate,
+    /// Displays all retention locks.
+    RetentionLocks,
+    /// Protects versions of an encrypted archive path and its nested paths
+    /// from being purged or merged into snapshots.
+    ///
+    /// Retention locks can't be removed or weakened. Adding a lock for a path
+    /// that already has one keeps the longest retention period.
+    AddRetentionLock {
+        /// Encrypted archive path (starting with "enar:"), as shown by the client's `ls` command.
+        path: EncryptedArchivePath,
+        /// Versions recorded within this number of days are protected.
+        #[arg(long)]
+        days: u32,
+        /// Also reject requests that remove or reset the path.
+        #[arg(long)]
+        forbid_remove: bool,
+    },
 }
 
 #[tokio::main]
@@ -65,6 +86,23 @@ async fn main() -> anyhow::Result<()> {
             set_access_token(&pool, &name, &token).await?;
             println!("Successfully updated access token. New access token:\n{token}");
         }
+        Command::RetentionLocks => {
+            let locks = retention_locks(&pool).await?;
+            if locks.is_empty() {
+                println!("No configured retention locks.");
+            }
+            for lock in locks {
+                println!("{lock}");
+            }
+        }
+        Command::AddRetentionLock {
+            path,
+            days,
+            forbid_remove,
+        } => {
+            add_retention_lock(&pool, &path, days, forbid_remove).await?;
+            println!("Successfully added retention lock.");
+        }
         Command::Migrate => {
             println!("Running migrations...");
             rammingen_server::util::migrate(&pool).await?;
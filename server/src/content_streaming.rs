@@ -52,6 +52,7 @@ pub async fn upload(
             StatusCode::BAD_REQUEST
         })?;
         received_length += data.len() as u64;
+        ctx.metrics.uploaded_bytes.inc_by(data.len() as u64);
         block_in_place(|| file.write_all(data)).map_err(|err| {
             warn!(?err, "failed to write to content file");
             StatusCode::INTERNAL_SERVER_ERROR
@@ -86,10 +87,14 @@ pub async fn download(
             StatusCode::INTERNAL_SERVER_ERROR
         })?
         .len();
+    let metrics = ctx.metrics.clone();
     Ok(Response::builder()
         .header(CONTENT_LENGTH, len)
         .body(BodyExt::boxed(StreamBody::new(
-            stream_file(Arc::new(Mutex::new(file))).map(|bytes| Ok(Frame::data(bytes))),
+            stream_file(Arc::new(Mutex::new(file))).map(move |bytes| {
+                metrics.downloaded_bytes.inc_by(bytes.len() as u64);
+                Ok(Frame::data(bytes))
+            }),
         )))
         .expect("response builder failed"))
 }
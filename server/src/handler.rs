@@ -1,23 +1,34 @@
+use std::cmp::min;
 use std::collections::HashMap;
+use std::time::Duration;
 use std::{collections::HashSet, sync::Arc};
 
 use anyhow::{anyhow, bail, Result};
 use chrono::{TimeZone, Utc};
-use futures_util::{future::BoxFuture, Stream, TryStreamExt};
+use futures_util::{
+    future::{self, BoxFuture},
+    Stream, TryStreamExt,
+};
 use rammingen_protocol::endpoints::{
     AddVersion, AddVersionResponse, AddVersions, BulkActionStats, CheckIntegrity,
-    ContentHashExists, GetAllEntryVersions, GetDirectChildEntries, GetEntryVersionsAtTime,
-    GetNewEntries, GetServerStatus, GetSources, MovePath, RemovePath, ResetVersion, Response,
-    ServerStatus, SourceInfo, StreamingResponseItem,
+    ContentHashExists, CopyPath, GetAllEntryVersions, GetDirectChildEntries,
+    GetEntryVersionsAtTime, GetNewEntries, GetOperations, GetServerStatus, GetSources, MovePath,
+    PathChange, PreviewMovePath, PreviewRemovePath, PreviewResetVersion, PurgePath, PurgeStats,
+    RemovePath, ResetVersion, Response, ServerStatus, SourceInfo, StreamingResponseItem,
+    UndoOperation, WaitForNewEntries,
 };
 use rammingen_protocol::{
     entry_kind_from_db, entry_kind_to_db, DateTimeUtc, EncryptedArchivePath, EncryptedContentHash,
-    EncryptedSize, Entry, EntryKind, EntryVersion, EntryVersionData, FileContent, RecordTrigger,
-    SourceId,
+    EncryptedSize, Entry, EntryKind, EntryVersion, EntryVersionData, FileContent, Operation,
+    OperationArgs, OperationId, RecordTrigger, SourceId,
 };
 use sqlx::{query, query_scalar, types::time::OffsetDateTime, PgPool, Postgres, Transaction};
-use tokio::sync::mpsc::Sender;
+use tokio::select;
+use tokio::sync::{mpsc::Sender, watch};
+use tokio::time::{timeout_at, Instant};
+use tracing::{info, warn};
 
+use crate::metrics::Metrics;
 use crate::storage::Storage;
 
 #[derive(Debug, Clone)]
@@ -25,8 +36,16 @@ pub struct Context {
     pub db_pool: PgPool,
     pub storage: Arc<Storage>,
     pub source_id: SourceId,
+    /// Notified every time a change of entries is committed.
+    pub entries_updated: Arc<watch::Sender<()>>,
+    pub metrics: Arc<Metrics>,
+    /// Becomes `true` when the server starts shutting down.
+    pub shutdown: watch::Receiver<bool>,
 }
 
+/// Max time a `WaitForNewEntries` request can wait for updates.
+const MAX_WAIT_FOR_NEW_ENTRIES_TIMEOUT: Duration = Duration::from_secs(60);
+
 macro_rules! convert_entry {
     ($row:expr) => {{
         let row = $row;
@@ -215,6 +234,7 @@ async fn add_version_inner<'a>(
             return Ok(AddVersionResponse { added: false });
         }
         if request.kind.is_none() {
+            check_remove_allowed(&request.path, &mut *tx).await?;
             let child_count = query_scalar!(
                 "SELECT count(*) FROM entries
                 WHERE kind != 0 AND parent_dir = $1",
@@ -308,14 +328,83 @@ async fn add_version_inner<'a>(
     Ok(AddVersionResponse { added: true })
 }
 
+/// Records a request that changed entries.
+async fn record_operation(
+    ctx: &Context,
+    args: &OperationArgs,
+    affected_paths: u64,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<OperationId> {
+    let id = query_scalar!(
+        "INSERT INTO operations (source_id, recorded_at, endpoint, args, affected_paths)
+        VALUES ($1, now(), $2, $3, $4)
+        RETURNING id",
+        ctx.source_id.to_db(),
+        args.endpoint(),
+        bincode::serialize(args)?,
+        i64::try_from(affected_paths)?,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    Ok(id.into())
+}
+
+/// Records a bulk operation before performing it. All versions added later in the transaction
+/// are linked to the operation by the `on_entry_update` trigger, so the operation can be undone.
+async fn start_operation(
+    ctx: &Context,
+    args: &OperationArgs,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<OperationId> {
+    let id = record_operation(ctx, args, 0, tx).await?;
+    query_scalar!(
+        "SELECT set_config('rammingen.operation_id', $1, true)",
+        id.to_string(),
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    Ok(id)
+}
+
+async fn finish_operation(
+    id: OperationId,
+    affected_paths: u64,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<BulkActionStats> {
+    query!(
+        "UPDATE operations SET affected_paths = $1 WHERE id = $2",
+        i64::try_from(affected_paths)?,
+        id.to_db(),
+    )
+    .execute(&mut *tx)
+    .await?;
+    Ok(BulkActionStats {
+        affected_paths,
+        operation_id: id,
+    })
+}
+
 pub async fn add_versions(ctx: Context, request: AddVersions) -> Result<Response<AddVersions>> {
+    let _content_lock = ctx.content_lock.read().await;
     let mut tx = ctx.db_pool.begin().await?;
     let mut results = Vec::new();
+    let mut added_paths = Vec::new();
     for item in request.0 {
+        let path = item.path.clone();
         let r = add_version_inner(&ctx, item, &mut tx).await?;
+        if r.added {
+            added_paths.push(path);
+        }
         results.push(r);
     }
+    // Requests that didn't change anything are not recorded because sync sends them often.
+    if !added_paths.is_empty() {
+        let affected_paths = added_paths.len().try_into()?;
+        let args = OperationArgs::AddVersions { paths: added_paths };
+        record_operation(&ctx, &args, affected_paths, &mut tx).await?;
+    }
     tx.commit().await?;
+    ctx.entries_updated.send_replace(());
     Ok(results)
 }
 
@@ -335,6 +424,46 @@ pub async fn get_new_entries(
     Ok(())
 }
 
+pub async fn wait_for_new_entries(
+    ctx: Context,
+    request: WaitForNewEntries,
+    tx: Sender<Result<StreamingResponseItem<WaitForNewEntries>>>,
+) -> Result<()> {
+    // Subscribe before checking the database so that no update can be missed.
+    let mut entries_updated = ctx.entries_updated.subscribe();
+    let mut shutdown = ctx.shutdown.clone();
+    let deadline = Instant::now() + min(request.timeout, MAX_WAIT_FOR_NEW_ENTRIES_TIMEOUT);
+    loop {
+        let has_new_entries = query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM entries WHERE update_number > $1)",
+            request.last_update_number.to_db()
+        )
+        .fetch_one(&ctx.db_pool)
+        .await?
+        .ok_or_else(|| anyhow!("expected 1 row in SELECT EXISTS query"))?;
+        if has_new_entries {
+            break;
+        }
+        select! {
+            r = timeout_at(deadline, entries_updated.changed()) => {
+                if r.is_err() {
+                    return Ok(());
+                }
+            }
+            // Don't delay shutdown until the timeout.
+            _ = shutdown.changed() => return Ok(()),
+        }
+    }
+    get_new_entries(
+        ctx,
+        GetNewEntries {
+            last_update_number: request.last_update_number,
+        },
+        tx,
+    )
+    .await
+}
+
 pub async fn get_direct_child_entries(
     ctx: Context,
     request: GetDirectChildEntries,
@@ -426,7 +555,7 @@ pub async fn get_all_entry_versions(
     Ok(())
 }
 
-fn starts_with(path: &EncryptedArchivePath) -> String {
+pub(crate) fn starts_with(path: &EncryptedArchivePath) -> String {
     if path.to_str_without_prefix() == "/" {
         "/%".into()
     } else {
@@ -470,71 +599,336 @@ async fn remove_entries_in_dir<'a>(
     Ok(r.rows_affected())
 }
 
-pub async fn move_path(ctx: Context, request: MovePath) -> Result<Response<MovePath>> {
-    let mut tx = ctx.db_pool.begin().await?;
-    let mut old_entries = Vec::new();
-    {
-        let count_existing = query_scalar!(
-            "SELECT COUNT(*) FROM entries WHERE (path = $1 OR path LIKE $2) AND kind > 0",
-            request.new_path.to_str_without_prefix(),
-            starts_with(&request.new_path)
+/// Entry changed by an operation.
+struct ChangedEntry {
+    path: EncryptedArchivePath,
+    /// Last version before the operation.
+    previous: Option<EntryVersionData>,
+    /// Last version added by the operation.
+    last: EntryVersionData,
+    /// True if the entry was changed after the operation.
+    changed_later: bool,
+}
+
+/// Returns all entries changed by the specified operation, ordered by path.
+async fn get_changed_entries(
+    operation_id: OperationId,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<ChangedEntry>> {
+    let rows = query!(
+        "SELECT
+            entries.id,
+            entries.path,
+            entries.update_number AS current_update_number,
+            MIN(entry_versions.update_number) AS first_update_number,
+            MAX(entry_versions.update_number) AS last_update_number
+        FROM entry_versions JOIN entries ON entries.id = entry_versions.entry_id
+        WHERE entry_versions.operation_id = $1
+        GROUP BY entries.id
+        ORDER BY entries.path",
+        operation_id.to_db()
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut changed_entries = Vec::new();
+    for row in rows {
+        let first_update_number = row
+            .first_update_number
+            .ok_or_else(|| anyhow!("missing first_update_number"))?;
+        let last_update_number = row
+            .last_update_number
+            .ok_or_else(|| anyhow!("missing last_update_number"))?;
+        let previous = query!(
+            "SELECT * FROM entry_versions
+            WHERE entry_id = $1 AND update_number < $2
+            ORDER BY update_number DESC
+            LIMIT 1",
+            row.id,
+            first_update_number,
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let last = query!(
+            "SELECT * FROM entry_versions WHERE entry_id = $1 AND update_number = $2",
+            row.id,
+            last_update_number,
         )
-        .fetch_one(&mut tx)
+        .fetch_one(&mut *tx)
+        .await?;
+        let previous = if let Some(previous) = previous {
+            Some(convert_version_data!(previous))
+        } else {
+            None
+        };
+        changed_entries.push(ChangedEntry {
+            path: EncryptedArchivePath::from_encrypted_without_prefix(&row.path)?,
+            previous,
+            last: convert_version_data!(last),
+            changed_later: row.current_update_number != last_update_number,
+        });
+    }
+    Ok(changed_entries)
+}
+
+/// Returns changes made by the operation within the current transaction.
+async fn get_path_changes(
+    operation_id: OperationId,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<Vec<PathChange>> {
+    Ok(get_changed_entries(operation_id, tx)
         .await?
-        .ok_or_else(|| anyhow!("expected 1 row in SELECT COUNT query"))?;
+        .into_iter()
+        .map(|entry| PathChange {
+            old: entry.previous,
+            new: entry.last,
+        })
+        .collect())
+}
 
-        if count_existing > 0 {
-            bail!("destination path already exists");
-        }
+/// Fails if `path` or any of its nested paths exists.
+async fn ensure_destination_is_free(
+    path: &EncryptedArchivePath,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<()> {
+    let count_existing = query_scalar!(
+        "SELECT COUNT(*) FROM entries WHERE (path = $1 OR path LIKE $2) AND kind > 0",
+        path.to_str_without_prefix(),
+        starts_with(path)
+    )
+    .fetch_one(&mut *tx)
+    .await?
+    .ok_or_else(|| anyhow!("expected 1 row in SELECT COUNT query"))?;
+
+    if count_existing > 0 {
+        bail!("destination path already exists");
+    }
+    Ok(())
+}
+
+/// Replaces `old_base` prefix of `path` with `new_base`.
+fn rebase_path(
+    path: &EncryptedArchivePath,
+    old_base: &EncryptedArchivePath,
+    new_base: &EncryptedArchivePath,
+) -> Result<EncryptedArchivePath> {
+    if path == old_base {
+        Ok(new_base.clone())
+    } else if let Some(relative) = path.strip_prefix(old_base) {
+        new_base.join_multiple(relative)
+    } else {
+        bail!("strip_prefix failed while processing path: {:?}", path);
+    }
+}
+
+pub async fn move_path(ctx: Context, request: MovePath) -> Result<Response<MovePath>> {
+    let _content_lock = ctx.content_lock.read().await;
+    let mut tx = ctx.db_pool.begin().await?;
+    let stats = move_path_inner(&ctx, request, &mut tx).await?;
+    tx.commit().await?;
+    ctx.entries_updated.send_replace(());
+    Ok(stats)
+}
 
+pub async fn preview_move_path(
+    ctx: Context,
+    request: PreviewMovePath,
+) -> Result<Response<PreviewMovePath>> {
+    let mut tx = ctx.db_pool.begin().await?;
+    let stats = move_path_inner(&ctx, request.0, &mut tx).await?;
+    let changes = get_path_changes(stats.operation_id, &mut tx).await?;
+    tx.rollback().await?;
+    Ok(changes)
+}
+
+async fn move_path_inner(
+    ctx: &Context,
+    request: MovePath,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<BulkActionStats> {
+    check_remove_allowed(&request.old_path, tx).await?;
+    let args = OperationArgs::MovePath {
+        old_path: request.old_path.clone(),
+        new_path: request.new_path.clone(),
+    };
+    let operation_id = start_operation(ctx, &args, &mut *tx).await?;
+    ensure_destination_is_free(&request.new_path, tx).await?;
+    let mut old_entries = Vec::new();
+    {
         let mut entries = query!(
             "SELECT * FROM entries WHERE (path = $1 OR path LIKE $2) AND kind > 0 ORDER BY path",
             request.old_path.to_str_without_prefix(),
             starts_with(&request.old_path),
         )
-        .fetch(&mut tx);
+        .fetch(&mut *tx);
         while let Some(row) = entries.try_next().await? {
             old_entries.push(convert_entry!(row));
         }
     }
 
-    remove_entries_in_dir(&ctx, &request.old_path, RecordTrigger::Move, &mut tx).await?;
+    remove_entries_in_dir(ctx, &request.old_path, RecordTrigger::Move, &mut *tx).await?;
 
     let affected_paths = old_entries.len().try_into()?;
     for entry in old_entries {
-        let new_path = if entry.data.path == request.old_path {
-            request.new_path.clone()
-        } else if let Some(relative) = entry.data.path.strip_prefix(&request.old_path) {
-            request.new_path.join_multiple(relative)?
-        } else {
-            bail!("strip_prefix failed while processing entry: {:?}", entry);
-        };
         let add_version = AddVersion {
-            path: new_path,
+            path: rebase_path(&entry.data.path, &request.old_path, &request.new_path)?,
             record_trigger: RecordTrigger::Move,
             kind: entry.data.kind,
             content: entry.data.content,
         };
-        let result = add_version_inner(&ctx, add_version, &mut tx).await?;
+        let result = add_version_inner(ctx, add_version, &mut *tx).await?;
         if !result.added {
             bail!("unexpected added = false while moving path");
         }
     }
 
+    finish_operation(operation_id, affected_paths, tx).await
+}
+
+pub async fn copy_path(ctx: Context, request: CopyPath) -> Result<Response<CopyPath>> {
+    let _content_lock = ctx.content_lock.read().await;
+    let mut tx = ctx.db_pool.begin().await?;
+    let args = OperationArgs::CopyPath {
+        old_path: request.old_path.clone(),
+        new_path: request.new_path.clone(),
+        recorded_at: request.recorded_at,
+    };
+    let operation_id = start_operation(&ctx, &args, &mut tx).await?;
+    ensure_destination_is_free(&request.new_path, &mut tx).await?;
+
+    let old_entries: Vec<EntryVersionData> = if let Some(recorded_at) = request.recorded_at {
+        get_versions_inner(recorded_at, &request.old_path, &mut tx)
+            .await?
+            .map_ok(|version| version.data)
+            .try_filter(|data| future::ready(data.kind.is_some()))
+            .try_collect()
+            .await?
+    } else {
+        query!(
+            "SELECT * FROM entries WHERE (path = $1 OR path LIKE $2) AND kind > 0 ORDER BY path",
+            request.old_path.to_str_without_prefix(),
+            starts_with(&request.old_path),
+        )
+        .fetch(&mut tx)
+        .map_err(anyhow::Error::from)
+        .and_then(|row| async move { Ok(convert_entry!(row).data) })
+        .try_collect()
+        .await?
+    };
+    if old_entries.is_empty() {
+        bail!("source path doesn't exist");
+    }
+
+    let affected_paths = old_entries.len().try_into()?;
+    for data in old_entries {
+        let add_version = AddVersion {
+            path: rebase_path(&data.path, &request.old_path, &request.new_path)?,
+            record_trigger: RecordTrigger::Copy,
+            kind: data.kind,
+            content: data.content,
+        };
+        let result = add_version_inner(&ctx, add_version, &mut tx).await?;
+        if !result.added {
+            bail!("unexpected added = false while copying path");
+        }
+    }
+
+    let stats = finish_operation(operation_id, affected_paths, &mut tx).await?;
     tx.commit().await?;
-    Ok(BulkActionStats { affected_paths })
+    ctx.entries_updated.send_replace(());
+    Ok(stats)
+}
+
+/// Fails if a retention lock forbids removing, moving or resetting `path`
+/// (or any of its parents or nested paths).
+async fn check_remove_allowed(
+    path: &EncryptedArchivePath,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<()> {
+    let locked_path = query_scalar!(
+        "SELECT path FROM retention_locks
+        WHERE forbid_remove AND (path = $1 OR path LIKE $2 OR $1 LIKE nested_paths_pattern)
+        LIMIT 1",
+        path.to_str_without_prefix(),
+        starts_with(path),
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(locked_path) = locked_path {
+        bail!(
+            "cannot change {} because of retention lock on {}",
+            path,
+            EncryptedArchivePath::from_encrypted_without_prefix(&locked_path)?
+        );
+    }
+    Ok(())
 }
 
 pub async fn remove_path(ctx: Context, request: RemovePath) -> Result<Response<RemovePath>> {
     let mut tx = ctx.db_pool.begin().await?;
-    let affected_paths =
-        remove_entries_in_dir(&ctx, &request.path, RecordTrigger::Remove, &mut tx).await?;
+    let stats = remove_path_inner(&ctx, request, &mut tx).await?;
     tx.commit().await?;
-    Ok(BulkActionStats { affected_paths })
+    ctx.entries_updated.send_replace(());
+    Ok(stats)
+}
+
+pub async fn preview_remove_path(
+    ctx: Context,
+    request: PreviewRemovePath,
+) -> Result<Response<PreviewRemovePath>> {
+    let mut tx = ctx.db_pool.begin().await?;
+    let stats = remove_path_inner(&ctx, request.0, &mut tx).await?;
+    let changes = get_path_changes(stats.operation_id, &mut tx).await?;
+    tx.rollback().await?;
+    Ok(changes)
+}
+
+async fn remove_path_inner(
+    ctx: &Context,
+    request: RemovePath,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<BulkActionStats> {
+    check_remove_allowed(&request.path, tx).await?;
+    let args = OperationArgs::RemovePath {
+        path: request.path.clone(),
+    };
+    let operation_id = start_operation(ctx, &args, &mut *tx).await?;
+    let affected_paths =
+        remove_entries_in_dir(ctx, &request.path, RecordTrigger::Remove, &mut *tx).await?;
+    finish_operation(operation_id, affected_paths, tx).await
 }
 
 pub async fn reset_version(ctx: Context, request: ResetVersion) -> Result<Response<ResetVersion>> {
+    let _content_lock = ctx.content_lock.read().await;
     let mut tx = ctx.db_pool.begin().await?;
+    let stats = reset_version_inner(&ctx, request, &mut tx).await?;
+    tx.commit().await?;
+    ctx.entries_updated.send_replace(());
+    Ok(stats)
+}
+
+pub async fn preview_reset_version(
+    ctx: Context,
+    request: PreviewResetVersion,
+) -> Result<Response<PreviewResetVersion>> {
+    let mut tx = ctx.db_pool.begin().await?;
+    let stats = reset_version_inner(&ctx, request.0, &mut tx).await?;
+    let changes = get_path_changes(stats.operation_id, &mut tx).await?;
+    tx.rollback().await?;
+    Ok(changes)
+}
+
+async fn reset_version_inner(
+    ctx: &Context,
+    request: ResetVersion,
+    tx: &mut Transaction<'_, Postgres>,
+) -> Result<BulkActionStats> {
+    check_remove_allowed(&request.path, tx).await?;
+    let args = OperationArgs::ResetVersion {
+        path: request.path.clone(),
+        recorded_at: request.recorded_at,
+    };
+    let operation_id = start_operation(ctx, &args, &mut *tx).await?;
 
     let old_existing_ids = query_scalar!(
         "SELECT id FROM entries
@@ -543,10 +937,10 @@ pub async fn reset_version(ctx: Context, request: ResetVersion) -> Result<Respon
         request.path.to_str_without_prefix(),
         starts_with(&request.path),
     )
-    .fetch_all(&mut tx)
+    .fetch_all(&mut *tx)
     .await?;
 
-    let entries: Vec<_> = get_versions_inner(request.recorded_at, &request.path, &mut tx)
+    let entries: Vec<_> = get_versions_inner(request.recorded_at, &request.path, &mut *tx)
         .await?
         .try_collect()
         .await?;
@@ -587,14 +981,14 @@ pub async fn reset_version(ctx: Context, request: ResetVersion) -> Result<Respon
     for entry in entries {
         if entry.data.kind.is_some() {
             let r = add_version_inner(
-                &ctx,
+                ctx,
                 AddVersion {
                     path: entry.data.path,
                     record_trigger: RecordTrigger::Reset,
                     kind: entry.data.kind,
                     content: entry.data.content,
                 },
-                &mut tx,
+                &mut *tx,
             )
             .await?;
             if r.added {
@@ -602,8 +996,226 @@ pub async fn reset_version(ctx: Context, request: ResetVersion) -> Result<Respon
             }
         }
     }
+    finish_operation(operation_id, affected_paths, tx).await
+}
+
+pub async fn purge_path(ctx: Context, request: PurgePath) -> Result<Response<PurgePath>> {
+    if !request.confirm {
+        bail!("purge of {} was not confirmed", request.path);
+    }
+    // Acquired before the transaction, so that it doesn't wait for requests
+    // that are blocked by its row locks.
+    let content_lock = ctx.content_lock.write().await;
+    let mut tx = ctx.db_pool.begin().await?;
+    let num_locked = query_scalar!(
+        "SELECT COUNT(*)
+        FROM entry_versions JOIN retention_locks
+            ON entry_versions.path = retention_locks.path
+            OR entry_versions.path LIKE retention_locks.nested_paths_pattern
+        WHERE (entry_versions.path = $1 OR entry_versions.path LIKE $2)
+            AND entry_versions.recorded_at >
+                now() - make_interval(days => retention_locks.retain_for_days)",
+        request.path.to_str_without_prefix(),
+        starts_with(&request.path),
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .ok_or_else(|| anyhow!("expected 1 row in SELECT COUNT query"))?;
+    if num_locked > 0 {
+        bail!(
+            "cannot purge {}: {} versions are protected by retention locks",
+            request.path,
+            num_locked
+        );
+    }
+
+    let mut hashes_to_check = HashSet::new();
+    let mut removed_versions = 0;
+    {
+        let mut deleted_rows = query_scalar!(
+            "DELETE FROM entry_versions
+            WHERE path = $1 OR path LIKE $2
+            RETURNING content_hash",
+            request.path.to_str_without_prefix(),
+            starts_with(&request.path),
+        )
+        .fetch(&mut tx);
+        while let Some(hash) = deleted_rows.try_next().await? {
+            removed_versions += 1;
+            if let Some(hash) = hash {
+                hashes_to_check.insert(EncryptedContentHash::from_encrypted(hash));
+            }
+        }
+    }
+    // Entries are kept as deleted with the `Purge` trigger, so that clients receive
+    // the update and remove their local copies like other deleted paths
+    // instead of uploading them again.
+    let removed_entries = query!(
+        "UPDATE entries
+        SET update_number = nextval('entry_update_numbers'),
+            recorded_at = now(),
+            source_id = $1,
+            record_trigger = $2,
+            kind = $3,
+            original_size = NULL,
+            encrypted_size = NULL,
+            modified_at = NULL,
+            content_hash = NULL,
+            unix_mode = NULL
+        WHERE path = $4 OR path LIKE $5",
+        ctx.source_id.to_db(),
+        RecordTrigger::Purge as i32,
+        EntryKind::NOT_EXISTS,
+        request.path.to_str_without_prefix(),
+        starts_with(&request.path),
+    )
+    .execute(&mut tx)
+    .await?
+    .rows_affected();
+
+    let mut hashes_to_remove = Vec::new();
+    for hash in hashes_to_check {
+        let exists = query_scalar!(
+            "SELECT 1 FROM entry_versions WHERE content_hash = $1 LIMIT 1",
+            hash.as_slice()
+        )
+        .fetch_optional(&mut tx)
+        .await?
+        .is_some();
+        if !exists {
+            hashes_to_remove.push(hash);
+        }
+    }
+
+    let args = OperationArgs::PurgePath { path: request.path };
+    record_operation(&ctx, &args, removed_entries, &mut tx).await?;
+    tx.commit().await?;
+    ctx.entries_updated.send_replace(());
+
+    let mut removed_content_files = 0;
+    for hash in hashes_to_remove {
+        match ctx.storage.remove_file(&hash) {
+            Ok(()) => removed_content_files += 1,
+            Err(err) => {
+                warn!(?err, "failed to remove content file");
+            }
+        }
+    }
+    drop(content_lock);
+    info!(
+        "purged path (removed {} entries, {} versions, {} files)",
+        removed_entries, removed_versions, removed_content_files,
+    );
+    Ok(PurgeStats {
+        removed_entries,
+        removed_versions,
+        removed_content_files,
+    })
+}
+
+pub async fn undo_operation(
+    ctx: Context,
+    request: UndoOperation,
+) -> Result<Response<UndoOperation>> {
+    let _content_lock = ctx.content_lock.read().await;
+    let mut tx = ctx.db_pool.begin().await?;
+    let operation = query!(
+        "SELECT endpoint, affected_paths FROM operations WHERE id = $1",
+        request.id.to_db()
+    )
+    .fetch_optional(&mut tx)
+    .await?
+    .ok_or_else(|| anyhow!("operation not found"))?;
+    if operation.endpoint == "AddVersions" || operation.endpoint == "PurgePath" {
+        bail!("only operations that return BulkActionStats can be undone");
+    }
+
+    let args = OperationArgs::UndoOperation {
+        id: request.id,
+        force: request.force,
+    };
+    let operation_id = start_operation(&ctx, &args, &mut tx).await?;
+
+    let changed_entries = get_changed_entries(request.id, &mut tx).await?;
+    // Snapshots remove old versions, including the ones linked to the operation.
+    // A move changes both the old and the new paths. Parent directories created
+    // implicitly are not counted in `affected_paths`, so only a shortage is an error.
+    let expected_entries = if operation.endpoint == "MovePath" {
+        operation.affected_paths.saturating_mul(2)
+    } else {
+        operation.affected_paths
+    };
+    if i64::try_from(changed_entries.len())? < expected_entries {
+        bail!(
+            "operation history was compacted: {} of {} changed paths remain",
+            changed_entries.len(),
+            expected_entries
+        );
+    }
+    let num_changed_later = changed_entries
+        .iter()
+        .filter(|entry| entry.changed_later)
+        .count();
+    let restored = changed_entries.into_iter().map(|entry| {
+        let (kind, content) = if let Some(previous) = entry.previous {
+            (previous.kind, previous.content)
+        } else {
+            (None, None)
+        };
+        AddVersion {
+            path: entry.path,
+            record_trigger: RecordTrigger::Reset,
+            kind,
+            content,
+        }
+    });
+    if num_changed_later > 0 && !request.force {
+        bail!(
+            "{} affected paths were changed after the operation, use force to undo anyway",
+            num_changed_later
+        );
+    }
+
+    // Deletions are applied to nested paths first, other changes are applied to parents first.
+    let (deletions, updates): (Vec<_>, Vec<_>) =
+        restored.partition(|version| version.kind.is_none());
+    for version in &deletions {
+        check_remove_allowed(&version.path, &mut tx).await?;
+    }
+    let mut affected_paths = 0;
+    for version in deletions.into_iter().rev().chain(updates) {
+        if add_version_inner(&ctx, version, &mut tx).await?.added {
+            affected_paths += 1;
+        }
+    }
+    let stats = finish_operation(operation_id, affected_paths, &mut tx).await?;
     tx.commit().await?;
-    Ok(BulkActionStats { affected_paths })
+    ctx.entries_updated.send_replace(());
+    Ok(stats)
+}
+
+pub async fn get_operations(
+    ctx: Context,
+    request: GetOperations,
+    tx: Sender<Result<StreamingResponseItem<GetOperations>>>,
+) -> Result<()> {
+    let since = request.since.unwrap_or_else(|| Utc.timestamp_nanos(0));
+    let mut rows = query!(
+        "SELECT * FROM operations WHERE recorded_at >= $1 ORDER BY recorded_at, id",
+        since.to_db()?,
+    )
+    .fetch(&ctx.db_pool);
+    while let Some(row) = rows.try_next().await? {
+        let operation = Operation {
+            id: row.id.into(),
+            source_id: row.source_id.into(),
+            recorded_at: row.recorded_at.from_db(),
+            args: bincode::deserialize(&row.args)?,
+            affected_paths: row.affected_paths.try_into()?,
+        };
+        tx.send(Ok(operation)).await?;
+    }
+    Ok(())
 }
 
 pub async fn check_integrity(
@@ -0,0 +1,79 @@
+use std::{
+    convert::Infallible,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::{body::Bytes, Response, StatusCode};
+use sqlx::query_scalar;
+use tokio::task::block_in_place;
+use tracing::warn;
+
+use crate::Context;
+
+const STORAGE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Reports that the process is up.
+pub fn healthz() -> Response<BoxBody<Bytes, Infallible>> {
+    text_response(StatusCode::OK)
+}
+
+/// Reports whether the server is able to handle requests.
+///
+/// The endpoint doesn't require authentication, so error details are only logged.
+pub async fn readyz(ctx: &Context) -> Response<BoxBody<Bytes, Infallible>> {
+    let mut errors = Vec::new();
+    if let Err(err) = check_database(ctx).await {
+        errors.push(format!("database: {err}"));
+    }
+    if let Err(err) = check_storage(ctx).await {
+        errors.push(format!("storage: {err}"));
+    }
+    if let Err(err) = block_in_place(|| check_available_space(ctx)) {
+        errors.push(format!("available space: {err}"));
+    }
+
+    if errors.is_empty() {
+        text_response(StatusCode::OK)
+    } else {
+        warn!(?errors, "readiness check failed");
+        text_response(StatusCode::SERVICE_UNAVAILABLE)
+    }
+}
+
+async fn check_database(ctx: &Context) -> Result<()> {
+    query_scalar!("SELECT 1").fetch_one(&ctx.db_pool).await?;
+    Ok(())
+}
+
+/// Checks that the storage is writable. A successful result is reused for
+/// `STORAGE_CHECK_INTERVAL`, so that frequent probes don't write to the storage.
+async fn check_storage(ctx: &Context) -> Result<()> {
+    let mut checked_at = ctx.storage_checked_at.lock().await;
+    if checked_at.is_some_and(|time| time.elapsed() < STORAGE_CHECK_INTERVAL) {
+        return Ok(());
+    }
+    block_in_place(|| ctx.storage.check_writable())?;
+    *checked_at = Some(Instant::now());
+    Ok(())
+}
+
+fn check_available_space(ctx: &Context) -> Result<()> {
+    let available_space = ctx.storage.available_space()?;
+    if available_space < ctx.config.min_available_space {
+        bail!(
+            "{} bytes available, expected at least {}",
+            available_space,
+            ctx.config.min_available_space
+        );
+    }
+    Ok(())
+}
+
+fn text_response(status: StatusCode) -> Response<BoxBody<Bytes, Infallible>> {
+    Response::builder()
+        .status(status)
+        .body(Full::new(Bytes::from(status.as_str().to_string())).boxed())
+        .expect("response builder failed")
+}
@@ -2,6 +2,8 @@
 
 mod content_streaming;
 mod handler;
+mod health;
+mod metrics;
 mod snapshot;
 mod storage;
 pub mod util;
@@ -30,9 +32,11 @@ use hyper::{
 };
 use rammingen_protocol::{
     endpoints::{
-        AddVersions, CheckIntegrity, ContentHashExists, GetAllEntryVersions, GetDirectChildEntries,
-        GetEntryVersionsAtTime, GetNewEntries, GetServerStatus, GetSources, MovePath, RemovePath,
-        RequestToResponse, RequestToStreamingResponse, ResetVersion, StreamingResponseItem,
+        AddVersions, CheckIntegrity, ContentHashExists, CopyPath, GetAllEntryVersions,
+        GetDirectChildEntries, GetEntryVersionsAtTime, GetNewEntries, GetOperations,
+        GetServerStatus, GetSources, MovePath, PreviewMovePath, PreviewRemovePath,
+        PreviewResetVersion, PurgePath, RemovePath, RequestToResponse, RequestToStreamingResponse,
+        ResetVersion, StreamingResponseItem, UndoOperation, WaitForNewEntries,
     },
     EncryptedContentHash, SourceId,
 };
@@ -46,14 +50,15 @@ use tokio::{
     signal::ctrl_c,
     sync::{
         mpsc::{self, Sender},
-        Mutex,
+        watch, Mutex, RwLock,
     },
     task,
-    time::interval,
+    time::{interval, timeout},
 };
 use tracing::{error, info, warn};
 use util::default_config_dir;
 
+use crate::metrics::{handle_metrics_request, Metrics};
 use crate::snapshot::make_snapshot;
 
 const SOURCES_CACHE_INTERVAL: Duration = Duration::from_secs(10);
@@ -63,6 +68,10 @@ pub struct Config {
     pub database_url: String,
     pub storage_path: PathBuf,
     pub bind_addr: SocketAddr,
+    /// Address of the Prometheus metrics endpoint (`/metrics`).
+    /// If omitted, metrics are not served.
+    #[serde(default)]
+    pub metrics_bind_addr: Option<SocketAddr>,
     #[serde(default)]
     pub log_file: Option<PathBuf>,
     #[serde(default = "default_log_filter")]
@@ -75,6 +84,12 @@ pub struct Config {
         default = "default_retain_detailed_history_for"
     )]
     pub retain_detailed_history_for: Duration,
+    /// Min available space on storage (in bytes) required for `/readyz` to report success.
+    #[serde(default)]
+    pub min_available_space: u64,
+    /// Max time to wait for active requests and snapshots to finish on shutdown.
+    #[serde(with = "humantime_serde", default = "default_shutdown_timeout")]
+    pub shutdown_timeout: Duration,
 }
 
 fn default_snapshot_interval() -> Duration {
@@ -85,6 +100,10 @@ fn default_retain_detailed_history_for() -> Duration {
     parse_duration("1week").unwrap()
 }
 
+fn default_shutdown_timeout() -> Duration {
+    parse_duration("30s").unwrap()
+}
+
 impl Config {
     pub fn parse(config_path: impl AsRef<Path>) -> Result<Self> {
         Ok(json5::from_str(&fs_err::read_to_string(config_path)?)?)
@@ -100,6 +119,16 @@ pub struct Context {
     db_pool: PgPool,
     storage: Arc<Storage>,
     sources: Arc<Mutex<CachedSources>>,
+    entries_updated: Arc<watch::Sender<()>>,
+    metrics: Arc<Metrics>,
+    /// Last time `/readyz` successfully checked that the storage is writable.
+    storage_checked_at: Arc<Mutex<Option<Instant>>>,
+    /// Requests that add references to content files hold it for reading until their
+    /// transaction is committed. Removal of unused content files holds it for writing,
+    /// so that a file can't be referenced again between the check and the removal.
+    content_lock: Arc<RwLock<()>>,
+    /// Becomes `true` when the server starts shutting down.
+    shutdown: watch::Receiver<bool>,
     config: Config,
 }
 
@@ -122,6 +151,7 @@ pub async fn run(config: Config) -> Result<()> {
     info!("Connecting to database...");
     let db_pool = PgPool::connect(&config.database_url).await?;
     info!("Connected to database.");
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
     let ctx = Context {
         config: config.clone(),
         storage: Arc::new(Storage::new(config.storage_path)?),
@@ -129,18 +159,70 @@ pub async fn run(config: Config) -> Result<()> {
             sources: load_sources(&db_pool).await?,
             updated_at: Instant::now(),
         })),
+        entries_updated: Arc::new(watch::channel(()).0),
+        metrics: Arc::new(Metrics::new()?),
+        storage_checked_at: Arc::new(Mutex::new(None)),
+        content_lock: Arc::new(RwLock::new(())),
+        shutdown: shutdown_receiver,
         db_pool,
     };
+    // Each task that should be finished before shutdown holds a sender.
+    // The receiver gets `None` when all of them are dropped.
+    let (active_task, mut active_tasks_receiver) = mpsc::channel::<()>(1);
 
     let listener = TcpListener::bind(&config.bind_addr).await?;
     info!("Listening on {}", config.bind_addr);
 
+    if let Some(metrics_bind_addr) = config.metrics_bind_addr {
+        let metrics_listener = TcpListener::bind(metrics_bind_addr).await?;
+        info!("Serving metrics on {}", metrics_bind_addr);
+        let ctx = ctx.clone();
+        let mut shutdown = ctx.shutdown.clone();
+        task::spawn(async move {
+            loop {
+                let r = select! {
+                    biased;
+                    _ = shutdown.changed() => break,
+                    r = metrics_listener.accept() => r,
+                };
+                match r {
+                    Ok((stream, _)) => {
+                        let ctx = ctx.clone();
+                        tokio::spawn(async move {
+                            if let Err(err) = http1::Builder::new()
+                                .serve_connection(
+                                    stream,
+                                    service_fn(move |req| handle_metrics_request(ctx.clone(), req)),
+                                )
+                                .await
+                            {
+                                warn!(?err, "error while serving metrics connection");
+                            }
+                        });
+                    }
+                    Err(err) => warn!(?err, "failed to accept metrics connection"),
+                }
+            }
+        });
+    }
+
     let snapshot_check_interval = min(config.snapshot_interval / 2, Duration::from_secs(60));
     let ctx2 = ctx.clone();
+    let snapshot_task = active_task.clone();
     task::spawn(async move {
+        let _snapshot_task = snapshot_task;
+        let mut shutdown = ctx2.shutdown.clone();
         let mut interval = interval(snapshot_check_interval);
         loop {
-            interval.tick().await;
+            select! {
+                biased;
+                _ = shutdown.changed() => break,
+                _ = interval.tick() => {}
+            }
+            // Don't start a snapshot if shutdown was requested while waiting for the tick.
+            if *shutdown.borrow() {
+                break;
+            }
             if let Err(err) = make_snapshot(&ctx2).await {
                 error!(?err, "error while making snapshot");
             }
@@ -162,17 +244,30 @@ pub async fn run(config: Config) -> Result<()> {
                 break;
             }
             r = listener.accept() => match r {
-                Ok((stream, _)) => {
+                Ok((stream, remote_addr)) => {
                     let ctx = ctx.clone();
+                    let connection_task = active_task.clone();
                     tokio::spawn(async move {
-                        if let Err(err) = http1::Builder::new()
+                        let _connection_task = connection_task;
+                        let mut shutdown = ctx.shutdown.clone();
+                        let connection = http1::Builder::new()
                             .keep_alive(true)
                             .serve_connection(
                                 stream,
-                                service_fn(move |req| handle_request(ctx.clone(), req)),
-                            )
-                            .await
-                        {
+                                service_fn(move |req| {
+                                    handle_request(ctx.clone(), remote_addr, req)
+                                }),
+                            );
+                        tokio::pin!(connection);
+                        let result = select! {
+                            r = connection.as_mut() => r,
+                            _ = shutdown.changed() => {
+                                // Finishes the active request and closes the connection.
+                                connection.as_mut().graceful_shutdown();
+                                connection.await
+                            }
+                        };
+                        if let Err(err) = result {
                             warn!(?err, "error while serving HTTP connection");
                         }
                     });
@@ -181,6 +276,19 @@ pub async fn run(config: Config) -> Result<()> {
             }
         }
     }
+
+    drop(listener);
+    shutdown_sender.send_replace(true);
+    drop(active_task);
+    info!("Waiting for active requests to finish...");
+    if timeout(config.shutdown_timeout, active_tasks_receiver.recv())
+        .await
+        .is_err()
+    {
+        warn!("Shutdown timeout exceeded, interrupting active requests.");
+    } else {
+        info!("All requests finished.");
+    }
     Ok(())
 }
 
@@ -200,9 +308,22 @@ fn sigterm() -> Result<impl Future<Output = ()>> {
 
 async fn handle_request(
     ctx: Context,
+    remote_addr: SocketAddr,
     request: Request<body::Incoming>,
 ) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
-    try_handle_request(ctx, request).await.or_else(|code| {
+    let started_at = Instant::now();
+    let metrics = ctx.metrics.clone();
+    let path = request.uri().path().to_string();
+    let result = try_handle_request(ctx, remote_addr, request).await;
+    let status = match &result {
+        Ok(response) => response.status(),
+        Err(code) => *code,
+    };
+    // Paths of unauthorized requests are not recorded because they can be arbitrary.
+    if status != StatusCode::UNAUTHORIZED {
+        metrics.observe_request(&path, status, started_at.elapsed());
+    }
+    result.or_else(|code| {
         Ok(Response::builder()
             .status(code)
             .body(Full::new(Bytes::from(code.as_str().to_string())).boxed())
@@ -212,10 +333,19 @@ async fn handle_request(
 
 async fn try_handle_request(
     ctx: Context,
+    remote_addr: SocketAddr,
     request: Request<body::Incoming>,
 ) -> Result<Response<BoxBody<Bytes, Infallible>>, StatusCode> {
+    // Health checks don't require authentication.
+    match request.uri().path() {
+        "/healthz" => return Ok(health::healthz()),
+        "/readyz" => return Ok(health::readyz(&ctx).await),
+        _ => {}
+    }
+
     let source_id = auth(&ctx, &request).await.map_err(|err| {
-        warn!(?err, "auth error");
+        warn!(?err, %remote_addr, "auth error");
+        ctx.metrics.observe_auth_failure(None);
         StatusCode::UNAUTHORIZED
     })?;
 
@@ -223,6 +353,9 @@ async fn try_handle_request(
         db_pool: ctx.db_pool,
         storage: ctx.storage,
         source_id,
+        entries_updated: ctx.entries_updated,
+        metrics: ctx.metrics,
+        shutdown: ctx.shutdown,
     };
 
     let path = request.uri().path();
@@ -242,6 +375,8 @@ async fn try_handle_request(
         Err(StatusCode::NOT_FOUND)
     } else if path == GetNewEntries::PATH {
         wrap_stream(ctx, request, handler::get_new_entries).await
+    } else if path == WaitForNewEntries::PATH {
+        wrap_stream(ctx, request, handler::wait_for_new_entries).await
     } else if path == GetDirectChildEntries::PATH {
         wrap_stream(ctx, request, handler::get_direct_child_entries).await
     } else if path == GetEntryVersionsAtTime::PATH {
@@ -252,16 +387,30 @@ async fn try_handle_request(
         wrap_request(ctx, request, handler::add_versions).await
     } else if path == MovePath::PATH {
         wrap_request(ctx, request, handler::move_path).await
+    } else if path == CopyPath::PATH {
+        wrap_request(ctx, request, handler::copy_path).await
     } else if path == RemovePath::PATH {
         wrap_request(ctx, request, handler::remove_path).await
     } else if path == ResetVersion::PATH {
         wrap_request(ctx, request, handler::reset_version).await
+    } else if path == PreviewMovePath::PATH {
+        wrap_request(ctx, request, handler::preview_move_path).await
+    } else if path == PreviewRemovePath::PATH {
+        wrap_request(ctx, request, handler::preview_remove_path).await
+    } else if path == PreviewResetVersion::PATH {
+        wrap_request(ctx, request, handler::preview_reset_version).await
+    } else if path == PurgePath::PATH {
+        wrap_request(ctx, request, handler::purge_path).await
+    } else if path == UndoOperation::PATH {
+        wrap_request(ctx, request, handler::undo_operation).await
     } else if path == ContentHashExists::PATH {
         wrap_request(ctx, request, handler::content_hash_exists).await
     } else if path == GetServerStatus::PATH {
         wrap_request(ctx, request, handler::get_server_status).await
     } else if path == CheckIntegrity::PATH {
         wrap_request(ctx, request, handler::check_integrity).await
+    } else if path == GetOperations::PATH {
+        wrap_stream(ctx, request, handler::get_operations).await
     } else if path == GetSources::PATH {
         wrap_request(ctx, request, handler::get_sources).await
     } else {
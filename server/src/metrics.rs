@@ -0,0 +1,205 @@
+use std::{
+    convert::Infallible,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::{
+    body::{self, Bytes},
+    header::CONTENT_TYPE,
+    Method, Request, Response, StatusCode,
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use sqlx::query_scalar;
+use tokio::{sync::Mutex, task::block_in_place};
+use tracing::warn;
+
+use crate::Context;
+
+/// Gauges that require scanning the database are updated at most this often,
+/// so that frequent scrapes don't load the database.
+const DB_STATS_UPDATE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Prometheus metrics of the server.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    auth_failures: IntCounterVec,
+    pub uploaded_bytes: IntCounter,
+    pub downloaded_bytes: IntCounter,
+    pub snapshot_duration: Histogram,
+    storage_used_bytes: IntGauge,
+    storage_available_bytes: IntGauge,
+    entries: IntGauge,
+    entry_versions: IntGauge,
+    /// Last time the gauges that require scanning the database were updated.
+    db_stats_updated_at: Mutex<Option<Instant>>,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self> {
+        let registry = Registry::new_custom(Some("rammingen".into()), None)?;
+        let requests = IntCounterVec::new(
+            Opts::new("requests_total", "Number of handled requests"),
+            &["path", "status"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "request_duration_seconds",
+                "Time until the response headers were ready",
+            ),
+            &["path"],
+        )?;
+        registry.register(Box::new(request_duration.clone()))?;
+        let auth_failures = IntCounterVec::new(
+            Opts::new("auth_failures_total", "Number of failed authentications"),
+            &["source"],
+        )?;
+        registry.register(Box::new(auth_failures.clone()))?;
+        let uploaded_bytes =
+            IntCounter::new("uploaded_bytes_total", "Received content size in bytes")?;
+        registry.register(Box::new(uploaded_bytes.clone()))?;
+        let downloaded_bytes =
+            IntCounter::new("downloaded_bytes_total", "Sent content size in bytes")?;
+        registry.register(Box::new(downloaded_bytes.clone()))?;
+        let snapshot_duration = Histogram::with_opts(
+            HistogramOpts::new("snapshot_duration_seconds", "Time spent making a snapshot")
+                .buckets(prometheus::exponential_buckets(0.1, 4.0, 8)?),
+        )?;
+        registry.register(Box::new(snapshot_duration.clone()))?;
+        let storage_used_bytes = IntGauge::new(
+            "storage_used_bytes",
+            "Total size of content referenced by the database",
+        )?;
+        registry.register(Box::new(storage_used_bytes.clone()))?;
+        let storage_available_bytes = IntGauge::new(
+            "storage_available_bytes",
+            "Available space on the storage file system",
+        )?;
+        registry.register(Box::new(storage_available_bytes.clone()))?;
+        let entries = IntGauge::new("entries", "Number of entries")?;
+        registry.register(Box::new(entries.clone()))?;
+        let entry_versions = IntGauge::new("entry_versions", "Number of entry versions")?;
+        registry.register(Box::new(entry_versions.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            request_duration,
+            auth_failures,
+            uploaded_bytes,
+            downloaded_bytes,
+            snapshot_duration,
+            storage_used_bytes,
+            storage_available_bytes,
+            entries,
+            entry_versions,
+            db_stats_updated_at: Mutex::new(None),
+        })
+    }
+
+    pub fn observe_request(&self, path: &str, status: StatusCode, duration: Duration) {
+        // Content hashes are not included to keep the number of labels bounded.
+        let path = if path.starts_with("/content/") {
+            "/content/"
+        } else {
+            path
+        };
+        self.requests
+            .with_label_values(&[path, status.as_str()])
+            .inc();
+        self.request_duration
+            .with_label_values(&[path])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Records a failed authentication. `source` is `None` if the request didn't carry
+    /// a valid access token. Such requests are counted as "unknown" so that arbitrary
+    /// tokens don't create new labels.
+    pub fn observe_auth_failure(&self, source: Option<&str>) {
+        self.auth_failures
+            .with_label_values(&[source.unwrap_or("unknown")])
+            .inc();
+    }
+
+    async fn render(&self, ctx: &Context) -> Result<String> {
+        {
+            let mut db_stats_updated_at = self.db_stats_updated_at.lock().await;
+            if !db_stats_updated_at.is_some_and(|time| time.elapsed() < DB_STATS_UPDATE_INTERVAL) {
+                self.update_db_stats(ctx).await?;
+                *db_stats_updated_at = Some(Instant::now());
+            }
+        }
+        self.storage_available_bytes.set(
+            block_in_place(|| ctx.storage.available_space())?
+                .try_into()
+                .unwrap_or(i64::MAX),
+        );
+
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8(buf)?)
+    }
+
+    async fn update_db_stats(&self, ctx: &Context) -> Result<()> {
+        let storage_used_bytes = query_scalar!(
+            "SELECT SUM(encrypted_size)::BIGINT FROM (
+                SELECT DISTINCT content_hash, encrypted_size
+                FROM entry_versions
+                WHERE content_hash IS NOT NULL
+            ) AS t"
+        )
+        .fetch_one(&ctx.db_pool)
+        .await?;
+        self.storage_used_bytes.set(storage_used_bytes.unwrap_or(0));
+        let entries = query_scalar!("SELECT COUNT(*) FROM entries")
+            .fetch_one(&ctx.db_pool)
+            .await?
+            .ok_or_else(|| anyhow!("expected 1 row in SELECT COUNT query"))?;
+        self.entries.set(entries);
+        let entry_versions = query_scalar!("SELECT COUNT(*) FROM entry_versions")
+            .fetch_one(&ctx.db_pool)
+            .await?
+            .ok_or_else(|| anyhow!("expected 1 row in SELECT COUNT query"))?;
+        self.entry_versions.set(entry_versions);
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+pub async fn handle_metrics_request(
+    ctx: Context,
+    request: Request<body::Incoming>,
+) -> Result<Response<BoxBody<Bytes, Infallible>>, Infallible> {
+    let response = if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::new(Bytes::from(StatusCode::NOT_FOUND.as_str().to_string())).boxed())
+    } else {
+        match ctx.metrics.render(&ctx).await {
+            Ok(text) => Response::builder()
+                .header(CONTENT_TYPE, TextEncoder::new().format_type())
+                .body(Full::new(Bytes::from(text)).boxed()),
+            Err(err) => {
+                warn!(?err, "failed to render metrics");
+                let code = StatusCode::INTERNAL_SERVER_ERROR;
+                Response::builder()
+                    .status(code)
+                    .body(Full::new(Bytes::from(code.as_str().to_string())).boxed())
+            }
+        }
+    };
+    Ok(response.expect("response builder failed"))
+}
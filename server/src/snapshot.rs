@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, time::Instant};
 
 use crate::handler::{FromDb, ToDb};
 use anyhow::Result;
@@ -11,6 +11,9 @@ use tracing::{info, warn};
 use crate::Context;
 
 pub async fn make_snapshot(ctx: &Context) -> Result<()> {
+    let started_at = Instant::now();
+    // Unused content files are removed at the end. See `Context::content_lock`.
+    let content_lock = ctx.content_lock.write().await;
     let mut tx = ctx.db_pool.begin().await?;
 
     let previous_snapshot_timestamp = if let Some(ts) =
@@ -55,7 +58,14 @@ pub async fn make_snapshot(ctx: &Context) -> Result<()> {
     {
         let mut deleted_rows = query_scalar!(
             "DELETE FROM entry_versions
-            WHERE recorded_at <= $1 AND snapshot_id IS NULL
+            WHERE recorded_at <= $1 AND snapshot_id IS NULL AND NOT EXISTS (
+                SELECT 1 FROM retention_locks
+                WHERE (
+                    entry_versions.path = retention_locks.path
+                    OR entry_versions.path LIKE retention_locks.nested_paths_pattern
+                ) AND entry_versions.recorded_at >
+                    now() - make_interval(days => retention_locks.retain_for_days)
+            )
             RETURNING content_hash",
             next_snapshot_timestamp_db,
         )
@@ -128,10 +138,14 @@ pub async fn make_snapshot(ctx: &Context) -> Result<()> {
         }
     }
 
+    drop(content_lock);
     info!(
         "created new snapshot for {} (deleted {} versions, added {} versions, removed {} files)",
         next_snapshot_timestamp, num_deleted, num_added, num_removed_files,
     );
+    ctx.metrics
+        .snapshot_duration
+        .observe(started_at.elapsed().as_secs_f64());
 
     Ok(())
 }
@@ -73,6 +73,19 @@ impl Storage {
         Ok(symlink_metadata(path)?.len())
     }
 
+    /// Checks that new files can be created in the storage.
+    pub fn check_writable(&self) -> Result<()> {
+        let mut file = self.create_file()?;
+        file.write_all(b"ok")?;
+        file.flush()?;
+        // The root is checked using a directory because `all_hashes_and_sizes`
+        // ignores empty directories but not unexpected files.
+        tempfile::Builder::new()
+            .prefix(".check")
+            .tempdir_in(&self.root)?;
+        Ok(())
+    }
+
     pub fn available_space(&self) -> Result<u64> {
         Ok(available_space(&self.root)?)
     }
@@ -1,8 +1,11 @@
 use anyhow::{bail, Result};
+use rammingen_protocol::EncryptedArchivePath;
 use rand::{distributions::Alphanumeric, distributions::DistString, rngs::OsRng};
 use sqlx::{query, query_scalar, PgPool};
 use std::path::PathBuf;
 
+use crate::handler::starts_with;
+
 pub async fn sources(db: &PgPool) -> Result<Vec<String>> {
     query_scalar!("SELECT name FROM sources ORDER BY name")
         .fetch_all(db)
@@ -37,6 +40,46 @@ pub async fn set_access_token(db: &PgPool, name: &str, access_token: &str) -> Re
     Ok(())
 }
 
+/// Adds a retention lock for `path` and all its nested paths.
+/// An existing lock for the same path can only be made stricter.
+pub async fn add_retention_lock(
+    db: &PgPool,
+    path: &EncryptedArchivePath,
+    retain_for_days: u32,
+    forbid_remove: bool,
+) -> Result<()> {
+    query!(
+        "INSERT INTO retention_locks (path, nested_paths_pattern, retain_for_days, forbid_remove)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (path) DO UPDATE SET
+            retain_for_days = GREATEST(retention_locks.retain_for_days, EXCLUDED.retain_for_days),
+            forbid_remove = retention_locks.forbid_remove OR EXCLUDED.forbid_remove",
+        path.to_str_without_prefix(),
+        starts_with(path),
+        i32::try_from(retain_for_days)?,
+        forbid_remove,
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn retention_locks(db: &PgPool) -> Result<Vec<String>> {
+    let rows = query!("SELECT * FROM retention_locks ORDER BY path")
+        .fetch_all(db)
+        .await?;
+    rows.into_iter()
+        .map(|row| {
+            let path = EncryptedArchivePath::from_encrypted_without_prefix(&row.path)?;
+            let mut text = format!("{path}: retain for {} days", row.retain_for_days);
+            if row.forbid_remove {
+                text.push_str(", remove and reset forbidden");
+            }
+            Ok(text)
+        })
+        .collect()
+}
+
 pub fn generate_access_token() -> String {
     Alphanumeric.sample_string(&mut OsRng, 64)
 }
@@ -1,4 +1,5 @@
 mod diff;
+mod scenarios;
 mod shuffle;
 
 use std::{
@@ -27,6 +28,7 @@ use rammingen_protocol::{util::native_to_archive_relative_path, ArchivePath, Dat
 use rammingen_server::util::{add_source, migrate};
 use rand::{seq::SliceRandom, thread_rng, Rng};
 use reqwest::Url;
+use scenarios::test_scenarios;
 use shuffle::{choose_path, random_content, random_name, shuffle};
 use sqlx::PgPool;
 use tempfile::TempDir;
@@ -70,6 +72,8 @@ pub enum Command {
     Random,
     Snapshot,
     ServerOnly,
+    /// Requires `--database-url`.
+    Scenarios,
 }
 
 async fn try_main() -> Result<()> {
@@ -82,6 +86,7 @@ async fn try_main() -> Result<()> {
         "info,sqlx=warn,rammingen_server=debug".into(),
     )?;
 
+    let mut server_db_pool = None;
     let server_url = if let Some(database_url) = cli.database_url {
         let db_pool = PgPool::connect(&database_url).await?;
         migrate(&db_pool).await?;
@@ -98,18 +103,25 @@ async fn try_main() -> Result<()> {
         };
         let server_config = rammingen_server::Config {
             bind_addr,
+            metrics_bind_addr: None,
             database_url: database_url.clone(),
             storage_path,
             log_file: None,
             log_filter: String::new(),
             retain_detailed_history_for: match &cli.command {
-                Command::Random | Command::ServerOnly => Duration::from_secs(3600),
+                Command::Random | Command::ServerOnly | Command::Scenarios => {
+                    Duration::from_secs(3600)
+                }
                 Command::Snapshot => Duration::from_secs(10),
             },
             snapshot_interval: match &cli.command {
-                Command::Random | Command::ServerOnly => Duration::from_secs(3600),
+                Command::Random | Command::ServerOnly | Command::Scenarios => {
+                    Duration::from_secs(3600)
+                }
                 Command::Snapshot => Duration::from_secs(5),
             },
+            min_available_space: 0,
+            shutdown_timeout: Duration::from_secs(10),
         };
         write(
             &dir.join("rammingen-server.conf"),
@@ -123,6 +135,7 @@ async fn try_main() -> Result<()> {
             )
             .await?;
         }
+        server_db_pool = Some(db_pool);
         tokio::spawn(async move {
             if let Err(err) = rammingen_server::run(server_config).await {
                 clear_status();
@@ -157,10 +170,14 @@ async fn try_main() -> Result<()> {
             encryption_key: encryption_key.clone(),
             server_url: server_url.clone(),
             access_token: access_token(client_index),
+            privacy_padding: false,
+            encrypt_local_db: client_index % 2 == 1,
             local_db_path: Some(client_dir.join("db")),
             log_file: None,
             log_filter: String::new(),
             warn_about_files_larger_than: "50 MB".parse().unwrap(),
+            mass_change_limits: Default::default(),
+            trash_retention: Duration::ZERO,
         };
         let config_path = client_dir.join("rammingen.conf");
         write(&config_path, json5::to_string(&config)?)?;
@@ -179,6 +196,12 @@ async fn try_main() -> Result<()> {
             info!("started server at {server_url}");
             pending().await
         }
+        Command::Scenarios => {
+            let Some(db_pool) = server_db_pool else {
+                bail!("database_url is required for scenarios");
+            };
+            test_scenarios(ctx, &db_pool).await
+        }
     }
 }
 
@@ -204,7 +227,7 @@ async fn test_random(ctx: Context) -> Result<()> {
             }
             copy_dir_all(&ctx.clients[0].mount_dir, &expected)?;
             let client1 = ctx.clients.choose(&mut thread_rng()).unwrap();
-            match thread_rng().gen_range(0..=4) {
+            match thread_rng().gen_range(0..=5) {
                 0 => {
                     // reset
                     let Some(snapshot_time_value) = snapshot_time else {
@@ -351,6 +374,27 @@ async fn test_random(ctx: Context) -> Result<()> {
                         client.sync().await?;
                     }
                 }
+                5 => {
+                    // copy path
+                    let Some(path1) = choose_path(&expected, true, true, false, false)? else {
+                        continue;
+                    };
+                    let path2_parent = choose_path(&expected, false, true, true, false)?.unwrap();
+                    let path2 = path2_parent.join(random_name(false));
+                    if path2.exists() || path2.starts_with(&path1) {
+                        continue;
+                    }
+                    if path1.is_dir() {
+                        copy_dir_all(&path1, &path2)?;
+                    } else {
+                        copy(&path1, &path2)?;
+                    }
+                    let archive_path = archive_subpath(&ctx.archive_mount_path, &expected, &path1)?;
+                    let new_archive_path =
+                        archive_subpath(&ctx.archive_mount_path, &expected, &path2)?;
+                    debug!("Checking cp ({archive_path} -> {new_archive_path})");
+                    client1.copy_path(archive_path, new_archive_path).await?;
+                }
                 _ => unreachable!(),
             }
             for client in &ctx.clients {
@@ -532,7 +576,8 @@ impl ClientData {
         rammingen::run(
             rammingen::cli::Cli {
                 config: None,
-                command: rammingen::cli::Command::Sync,
+                confirm_privacy_padding: false,
+                command: rammingen::cli::Command::Sync { force: false },
             },
             self.config.clone(),
         )
@@ -543,6 +588,7 @@ impl ClientData {
         rammingen::run(
             rammingen::cli::Cli {
                 config: None,
+                confirm_privacy_padding: false,
                 command: rammingen::cli::Command::DryRun,
             },
             self.config.clone(),
@@ -559,6 +605,7 @@ impl ClientData {
         rammingen::run(
             rammingen::cli::Cli {
                 config: None,
+                confirm_privacy_padding: false,
                 command: rammingen::cli::Command::Download {
                     archive_path,
                     local_path,
@@ -578,6 +625,7 @@ impl ClientData {
         rammingen::run(
             rammingen::cli::Cli {
                 config: None,
+                confirm_privacy_padding: false,
                 command: rammingen::cli::Command::Upload {
                     local_path,
                     archive_path,
@@ -596,9 +644,31 @@ impl ClientData {
         rammingen::run(
             rammingen::cli::Cli {
                 config: None,
+                confirm_privacy_padding: false,
                 command: rammingen::cli::Command::Move {
                     old_path: archive_path,
                     new_path: new_archive_path,
+                    dry_run: false,
+                },
+            },
+            self.config.clone(),
+        )
+        .await
+    }
+
+    async fn copy_path(
+        &self,
+        archive_path: ArchivePath,
+        new_archive_path: ArchivePath,
+    ) -> Result<()> {
+        rammingen::run(
+            rammingen::cli::Cli {
+                config: None,
+                confirm_privacy_padding: false,
+                command: rammingen::cli::Command::Copy {
+                    old_path: archive_path,
+                    new_path: new_archive_path,
+                    at: None,
                 },
             },
             self.config.clone(),
@@ -610,7 +680,11 @@ impl ClientData {
         rammingen::run(
             rammingen::cli::Cli {
                 config: None,
-                command: rammingen::cli::Command::Remove { archive_path },
+                confirm_privacy_padding: false,
+                command: rammingen::cli::Command::Remove {
+                    archive_path,
+                    dry_run: false,
+                },
             },
             self.config.clone(),
         )
@@ -621,9 +695,41 @@ impl ClientData {
         rammingen::run(
             rammingen::cli::Cli {
                 config: None,
+                confirm_privacy_padding: false,
                 command: rammingen::cli::Command::Reset {
                     archive_path,
                     version,
+                    dry_run: false,
+                },
+            },
+            self.config.clone(),
+        )
+        .await
+    }
+
+    async fn undo(&self, operation_id: i64) -> Result<()> {
+        rammingen::run(
+            rammingen::cli::Cli {
+                config: None,
+                confirm_privacy_padding: false,
+                command: rammingen::cli::Command::Undo {
+                    operation_id,
+                    force: false,
+                },
+            },
+            self.config.clone(),
+        )
+        .await
+    }
+
+    async fn adopt(&self, replace_mismatched: bool) -> Result<()> {
+        rammingen::run(
+            rammingen::cli::Cli {
+                config: None,
+                confirm_privacy_padding: false,
+                command: rammingen::cli::Command::Adopt {
+                    replace_mismatched,
+                    dry_run: false,
                 },
             },
             self.config.clone(),
@@ -635,6 +741,7 @@ impl ClientData {
         rammingen::run(
             rammingen::cli::Cli {
                 config: None,
+                confirm_privacy_padding: false,
                 command: rammingen::cli::Command::CheckIntegrity,
             },
             self.config.clone(),
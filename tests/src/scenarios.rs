@@ -0,0 +1,241 @@
+//! Deterministic checks of features that random tests don't cover.
+
+use anyhow::{bail, Result};
+use std::time::Duration;
+
+use fs_err::{create_dir, create_dir_all, read_to_string, remove_file, rename, write};
+use rammingen::{
+    config::MountPoint,
+    encryption::{encrypt_path, Cipher},
+};
+use rammingen_protocol::{ArchivePath, RecordTrigger};
+use rammingen_server::util::add_retention_lock;
+use sqlx::{query_as, query_scalar, PgPool};
+use tracing::info;
+
+use crate::{archive_subpath, ClientData, Context};
+
+pub async fn test_scenarios(ctx: Context, db_pool: &PgPool) -> Result<()> {
+    test_retention_locks(&ctx, db_pool).await?;
+    test_renames(&ctx, db_pool).await?;
+    test_adopt(&ctx, db_pool).await?;
+    Ok(())
+}
+
+fn cipher(client: &ClientData) -> Cipher {
+    Cipher::new(
+        client.config.encryption_key.get(),
+        client.config.privacy_padding,
+    )
+}
+
+struct ServerEntry {
+    kind: i32,
+    record_trigger: i32,
+    update_number: i64,
+}
+
+/// Returns the latest version of `path` on the server.
+async fn server_entry(
+    client: &ClientData,
+    db_pool: &PgPool,
+    path: &ArchivePath,
+) -> Result<Option<ServerEntry>> {
+    let encrypted = encrypt_path(path, &cipher(client))?;
+    let row: Option<(i32, i32, i64)> =
+        query_as("SELECT kind, record_trigger, update_number FROM entries WHERE path = $1")
+            .bind(encrypted.to_str_without_prefix())
+            .fetch_optional(db_pool)
+            .await?;
+    Ok(
+        row.map(|(kind, record_trigger, update_number)| ServerEntry {
+            kind,
+            record_trigger,
+            update_number,
+        }),
+    )
+}
+
+/// Returns kind of the latest version of `path` on the server.
+async fn server_kind(client: &ClientData, db_pool: &PgPool, path: &ArchivePath) -> Result<i32> {
+    Ok(server_entry(client, db_pool, path)
+        .await?
+        .map_or(0, |entry| entry.kind))
+}
+
+fn expect_err(result: Result<()>, action: &str) -> Result<()> {
+    if result.is_ok() {
+        bail!("expected {action} to fail");
+    }
+    Ok(())
+}
+
+/// Checks that no request can delete a path protected by a retention lock.
+async fn test_retention_locks(ctx: &Context, db_pool: &PgPool) -> Result<()> {
+    info!("Checking retention locks");
+    let client = &ctx.clients[0];
+    let archive_path = |relative: &str| {
+        archive_subpath(
+            &ctx.archive_mount_path,
+            &client.mount_dir,
+            &client.mount_dir.join(relative),
+        )
+    };
+    let locked_dir = client.mount_dir.join("locked");
+    create_dir_all(locked_dir.join("dir"))?;
+    write(locked_dir.join("file1"), "content1")?;
+    write(locked_dir.join("dir").join("file2"), "content2")?;
+    client.sync().await?;
+    add_retention_lock(
+        db_pool,
+        &encrypt_path(&archive_path("locked")?, &cipher(client))?,
+        1,
+        true,
+    )
+    .await?;
+
+    expect_err(
+        client
+            .move_path(archive_path("locked")?, archive_path("moved")?)
+            .await,
+        "move of locked path",
+    )?;
+    if server_kind(client, db_pool, &archive_path("locked/dir/file2")?).await? == 0 {
+        bail!("locked path was moved");
+    }
+
+    // Sync sends `AddVersion` with no kind for deleted files.
+    remove_file(locked_dir.join("file1"))?;
+    expect_err(client.sync().await, "sync of deleted locked file")?;
+    if server_kind(client, db_pool, &archive_path("locked/file1")?).await? == 0 {
+        bail!("locked file was deleted by sync");
+    }
+    write(locked_dir.join("file1"), "content1")?;
+    client.sync().await?;
+
+    // Undo of a copy would delete the copied paths.
+    client
+        .copy_path(archive_path("locked/dir")?, archive_path("locked/copy")?)
+        .await?;
+    let operation_id: i64 = query_scalar("SELECT MAX(id) FROM operations")
+        .fetch_one(db_pool)
+        .await?;
+    expect_err(
+        client.undo(operation_id).await,
+        "undo of copy to locked path",
+    )?;
+    if server_kind(client, db_pool, &archive_path("locked/copy/file2")?).await? == 0 {
+        bail!("locked path was deleted by undo");
+    }
+    client.sync().await?;
+    Ok(())
+}
+
+/// Checks that local renames are recorded as moves on the server.
+async fn test_renames(ctx: &Context, db_pool: &PgPool) -> Result<()> {
+    info!("Checking renames");
+    let client = &ctx.clients[0];
+    let archive_path = |relative: &str| {
+        archive_subpath(
+            &ctx.archive_mount_path,
+            &client.mount_dir,
+            &client.mount_dir.join(relative),
+        )
+    };
+    let dir = client.mount_dir.join("renames");
+    create_dir_all(dir.join("dir1"))?;
+    write(dir.join("dir1").join("file1"), "content1")?;
+    write(dir.join("file2"), "content2")?;
+    client.sync().await?;
+
+    rename(dir.join("dir1"), dir.join("dir2"))?;
+    rename(dir.join("file2"), dir.join("file3"))?;
+    client.sync().await?;
+    for (old_path, new_path) in [
+        ("renames/dir1", "renames/dir2"),
+        ("renames/dir1/file1", "renames/dir2/file1"),
+        ("renames/file2", "renames/file3"),
+    ] {
+        if server_kind(client, db_pool, &archive_path(old_path)?).await? != 0 {
+            bail!("{old_path} still exists after rename");
+        }
+        let trigger = server_entry(client, db_pool, &archive_path(new_path)?)
+            .await?
+            .map(|entry| RecordTrigger::try_from(entry.record_trigger))
+            .transpose()?;
+        if trigger != Some(RecordTrigger::Move) {
+            bail!("{old_path} was not moved to {new_path} (trigger: {trigger:?})");
+        }
+    }
+    Ok(())
+}
+
+/// Checks that adopt records matching files without uploading them
+/// and replaces mismatched files with the archive versions.
+async fn test_adopt(ctx: &Context, db_pool: &PgPool) -> Result<()> {
+    info!("Checking adopt");
+    let client = &ctx.clients[0];
+    let archive_path = |relative: &str| {
+        archive_subpath(
+            &ctx.archive_mount_path,
+            &client.mount_dir,
+            &client.mount_dir.join(relative),
+        )
+    };
+    let dir = client.mount_dir.join("adopt");
+    create_dir_all(&dir)?;
+    write(dir.join("same"), "same")?;
+    write(dir.join("mismatched"), "archive")?;
+    write(dir.join("wrong_kind"), "file")?;
+    client.sync().await?;
+
+    let mut entries = Vec::new();
+    for name in ["same", "mismatched", "wrong_kind"] {
+        let Some(entry) =
+            server_entry(client, db_pool, &archive_path(&format!("adopt/{name}"))?).await?
+        else {
+            bail!("adopt/{name} was not uploaded");
+        };
+        entries.push(entry);
+    }
+
+    // A new machine that already has a copy of the directory.
+    let new_client_dir = ctx.dir.join("adopt_client");
+    let mount_dir = new_client_dir.join("mount");
+    create_dir_all(&mount_dir)?;
+    write(mount_dir.join("same"), "same")?;
+    write(mount_dir.join("mismatched"), "local")?;
+    create_dir(mount_dir.join("wrong_kind"))?;
+    let mut config = client.config.clone();
+    config.mount_points = vec![MountPoint {
+        local_path: mount_dir.to_str().unwrap().parse()?,
+        archive_path: archive_path("adopt")?,
+        exclude: vec![],
+    }];
+    config.local_db_path = Some(new_client_dir.join("db"));
+    let mut new_client = ClientData { config, mount_dir };
+
+    // Mismatched files would be deleted permanently without the trash.
+    expect_err(new_client.adopt(true).await, "adopt without trash")?;
+
+    new_client.config.trash_retention = Duration::from_secs(3600);
+    new_client.adopt(true).await?;
+    new_client.sync().await?;
+
+    let same = server_entry(client, db_pool, &archive_path("adopt/same")?).await?;
+    if same.map(|entry| entry.update_number) != Some(entries[0].update_number) {
+        bail!("matching file was uploaded again");
+    }
+    let mismatched = server_entry(client, db_pool, &archive_path("adopt/mismatched")?).await?;
+    if mismatched.map(|entry| entry.update_number) != Some(entries[1].update_number) {
+        bail!("mismatched file was uploaded instead of being replaced");
+    }
+    if read_to_string(new_client.mount_dir.join("mismatched"))? != "archive" {
+        bail!("mismatched file was not replaced with the archive version");
+    }
+    // Directories are not replaced, so the local one is uploaded.
+    if server_kind(client, db_pool, &archive_path("adopt/wrong_kind")?).await? != 2 {
+        bail!("entry of a different kind was adopted");
+    }
+    Ok(())
+}